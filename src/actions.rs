@@ -4,7 +4,7 @@ use crate::error::Error;
 use casbin::{error::AdapterError, Error as CasbinError, Filter, Result};
 use sqlx::error::Error as SqlxError;
 
-use crate::entity::{CasbinRule, NewCasbinRule};
+use crate::entity::{ApiKey, CasbinRule, NewCasbinRule, PolicyVersion};
 
 use sqlx::mysql::MySqlQueryResult;
 
@@ -202,7 +202,111 @@ pub(crate) async fn load_policy(conn: &ConnectionPool) -> Result<Vec<CasbinRule>
     Ok(casbin_rule)
 }
 
-pub(crate) async fn load_filtered_policy<'a>(
+pub(crate) async fn policy_version(conn: &ConnectionPool) -> Result<PolicyVersion> {
+    let version: PolicyVersion = sqlx::query_as!(
+        PolicyVersion,
+        "SELECT COUNT(*) AS count,
+                BIT_XOR(CRC32(CONCAT_WS(',', ptype, v0, v1, v2, v3, v4, v5))) AS checksum
+            FROM akashic_policy"
+    )
+    .fetch_one(conn)
+    .await
+    .map_err(|err| CasbinError::from(AdapterError(Box::new(Error::SqlxError(err)))))?;
+
+    Ok(version)
+}
+
+pub async fn new_api_key(conn: &ConnectionPool) -> Result<MySqlQueryResult> {
+    sqlx::query!(
+        "CREATE TABLE IF NOT EXISTS akashic_api_key (
+                    id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+                    key_hash CHAR(64) NOT NULL,
+                    subject VARCHAR(255) NOT NULL,
+                    name VARCHAR(100) NOT NULL,
+                    path_scope VARCHAR(255),
+                    method_scope VARCHAR(100),
+                    expires_at BIGINT,
+                    last_used_at BIGINT,
+                    created_at BIGINT NOT NULL,
+                    CONSTRAINT unique_key_hash UNIQUE(key_hash)
+                ) ENGINE=InnoDB DEFAULT CHARSET=utf8;",
+    )
+    .execute(conn)
+    .await
+    .map_err(|err| CasbinError::from(AdapterError(Box::new(Error::SqlxError(err)))))
+}
+
+pub(crate) async fn add_api_key(
+    conn: &ConnectionPool,
+    key_hash: &str,
+    key: &ApiKey,
+) -> Result<i64> {
+    sqlx::query!(
+        "INSERT INTO akashic_api_key (key_hash, subject, name, path_scope, method_scope, expires_at, created_at)
+                 VALUES (?, ?, ?, ?, ?, ?, ?)",
+        key_hash,
+        key.subject,
+        key.name,
+        key.path_scope,
+        key.method_scope,
+        key.expires_at,
+        key.created_at
+    )
+    .execute(conn)
+    .await
+    .map(|n| n.last_insert_id() as i64)
+    .map_err(|err| CasbinError::from(AdapterError(Box::new(Error::SqlxError(err)))))
+}
+
+pub(crate) async fn find_api_key(conn: &ConnectionPool, key_hash: &str) -> Result<Option<ApiKey>> {
+    sqlx::query_as!(
+        ApiKey,
+        "SELECT id, subject, name, path_scope, method_scope, expires_at, last_used_at, created_at
+            FROM akashic_api_key WHERE key_hash = ?",
+        key_hash
+    )
+    .fetch_optional(conn)
+    .await
+    .map_err(|err| CasbinError::from(AdapterError(Box::new(Error::SqlxError(err)))))
+}
+
+pub(crate) async fn list_api_keys(conn: &ConnectionPool, subject: &str) -> Result<Vec<ApiKey>> {
+    sqlx::query_as!(
+        ApiKey,
+        "SELECT id, subject, name, path_scope, method_scope, expires_at, last_used_at, created_at
+            FROM akashic_api_key WHERE subject = ? ORDER BY id",
+        subject
+    )
+    .fetch_all(conn)
+    .await
+    .map_err(|err| CasbinError::from(AdapterError(Box::new(Error::SqlxError(err)))))
+}
+
+pub(crate) async fn touch_api_key(conn: &ConnectionPool, id: i64, now: i64) -> Result<()> {
+    sqlx::query!(
+        "UPDATE akashic_api_key SET last_used_at = ? WHERE id = ?",
+        now,
+        id
+    )
+    .execute(conn)
+    .await
+    .map(|_| ())
+    .map_err(|err| CasbinError::from(AdapterError(Box::new(Error::SqlxError(err)))))
+}
+
+pub(crate) async fn remove_api_key(conn: &ConnectionPool, id: i64, subject: &str) -> Result<bool> {
+    sqlx::query!(
+        "DELETE FROM akashic_api_key WHERE id = ? AND subject = ?",
+        id,
+        subject
+    )
+    .execute(conn)
+    .await
+    .map(|n| MySqlQueryResult::rows_affected(&n) == 1)
+    .map_err(|err| CasbinError::from(AdapterError(Box::new(Error::SqlxError(err)))))
+}
+
+pub(crate) async fn load_filtered_policy(
     conn: &ConnectionPool,
     filter: &Filter<'_>,
 ) -> Result<Vec<CasbinRule>> {
@@ -27,12 +27,41 @@ impl<'a> SqlxAdapter {
             .await
             .map_err(|err| casbin::Error::from(AdapterError(Box::new(Error::SqlxError(err)))))?;
 
-        adapter::new(&pool).await.map(|_| Self {
+        adapter::new(&pool).await?;
+        adapter::new_api_key(&pool).await.map(|_| Self {
             pool,
             is_filtered: Arc::new(AtomicBool::new(false)),
         })
     }
 
+    /// Fingerprint of the whole policy table, changes whenever a rule is added or removed.
+    pub async fn policy_version(&self) -> Result<PolicyVersion> {
+        adapter::policy_version(&self.pool).await
+    }
+
+    /// Store a new api key by the hash of its secret, return its id.
+    pub async fn add_api_key(&self, key_hash: &str, key: &ApiKey) -> Result<i64> {
+        adapter::add_api_key(&self.pool, key_hash, key).await
+    }
+
+    pub async fn find_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>> {
+        adapter::find_api_key(&self.pool, key_hash).await
+    }
+
+    pub async fn list_api_keys(&self, subject: &str) -> Result<Vec<ApiKey>> {
+        adapter::list_api_keys(&self.pool, subject).await
+    }
+
+    /// Record the time the api key was last used.
+    pub async fn touch_api_key(&self, id: i64, now: i64) -> Result<()> {
+        adapter::touch_api_key(&self.pool, id, now).await
+    }
+
+    /// Remove the api key of the subject, return false if it does not exist.
+    pub async fn remove_api_key(&self, id: i64, subject: &str) -> Result<bool> {
+        adapter::remove_api_key(&self.pool, id, subject).await
+    }
+
     pub(crate) fn save_policy_line(
         &self,
         ptype: &'a str,
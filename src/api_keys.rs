@@ -0,0 +1,104 @@
+use sha2::{Digest, Sha256};
+
+use crate::entity::ApiKey;
+use crate::login;
+
+/// Api keys are told apart from jwt access tokens by this prefix.
+const PREFIX: &str = "akashic_";
+
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(PREFIX)
+}
+
+/// A new api key secret with 256 bits of randomness.
+pub fn generate() -> String {
+    format!("{}{}", PREFIX, login::random_string())
+}
+
+/// Hash stored for the api key secret.
+/// Secrets are random enough that a fast hash does not make them guessable.
+pub fn hash(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+pub fn expired(key: &ApiKey, now: i64) -> bool {
+    key.expires_at.is_some_and(|expires_at| expires_at <= now)
+}
+
+/// Whether the request is within the path prefix and methods the key is restricted to.
+pub fn in_scope(key: &ApiKey, method: &str, path: &str) -> bool {
+    let path_allowed = key
+        .path_scope
+        .as_deref()
+        .is_none_or(|scope| {
+            // Match whole segments, "/api/orders" does not cover "/api/orders-admin"
+            path.strip_prefix(scope)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || scope.ends_with('/'))
+        });
+    let method_allowed = key.method_scope.as_deref().is_none_or(|scope| {
+        scope
+            .split(',')
+            .any(|allowed| allowed.trim().eq_ignore_ascii_case(method))
+    });
+    path_allowed && method_allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(path_scope: Option<&str>, method_scope: Option<&str>) -> ApiKey {
+        ApiKey {
+            id: 1,
+            subject: "org/alice".to_string(),
+            name: "ci".to_string(),
+            path_scope: path_scope.map(str::to_string),
+            method_scope: method_scope.map(str::to_string),
+            expires_at: None,
+            last_used_at: None,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn path_scope() {
+        let scoped = key(Some("/api/orders"), None);
+        assert!(in_scope(&scoped, "GET", "/api/orders"));
+        assert!(in_scope(&scoped, "GET", "/api/orders/1"));
+        assert!(!in_scope(&scoped, "GET", "/api/orders-admin"));
+        assert!(!in_scope(&scoped, "GET", "/api/ordersx/1"));
+        assert!(!in_scope(&scoped, "GET", "/api"));
+        assert!(!in_scope(&scoped, "GET", "/other/api/orders"));
+
+        let dir = key(Some("/api/"), None);
+        assert!(in_scope(&dir, "GET", "/api/orders"));
+        assert!(!in_scope(&dir, "GET", "/api"));
+        assert!(!in_scope(&dir, "GET", "/apix/orders"));
+
+        assert!(in_scope(&key(None, None), "DELETE", "/anything"));
+    }
+
+    #[test]
+    fn method_scope() {
+        let read_only = key(None, Some("GET, head"));
+        assert!(in_scope(&read_only, "GET", "/api"));
+        assert!(in_scope(&read_only, "HEAD", "/api"));
+        assert!(in_scope(&read_only, "get", "/api"));
+        assert!(!in_scope(&read_only, "POST", "/api"));
+        assert!(!in_scope(&read_only, "GE", "/api"));
+
+        let both = key(Some("/api/orders"), Some("POST"));
+        assert!(in_scope(&both, "POST", "/api/orders/1"));
+        assert!(!in_scope(&both, "GET", "/api/orders/1"));
+        assert!(!in_scope(&both, "POST", "/api/users"));
+    }
+
+    #[test]
+    fn expiry() {
+        let mut expiring = key(None, None);
+        assert!(!expired(&expiring, 100));
+        expiring.expires_at = Some(100);
+        assert!(expired(&expiring, 100));
+        assert!(!expired(&expiring, 99));
+    }
+}
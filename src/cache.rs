@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+
+use crate::entity::CasdoorUser;
+use crate::response::ActiveResponse;
+
+fn key(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+struct Entry {
+    resp: ActiveResponse,
+    expires_at: i64,
+}
+
+/// In-process cache for casdoor introspection results.
+/// Entries are keyed by the SHA-256 digest of the token so that raw tokens are never kept,
+/// and expire at the earlier of the configured ttl and the token's `exp`.
+pub struct IntrospectCache {
+    entries: Mutex<HashMap<[u8; 32], Entry>>,
+    ttl: i64,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl IntrospectCache {
+    /// Create a cache holding at most `capacity` entries for `ttl` seconds.
+    /// The cache is disabled if either of them is 0.
+    pub fn new(ttl: u64, capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            ttl: ttl as i64,
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.ttl > 0 && self.capacity > 0
+    }
+
+    /// Get the cached introspection result of the token if it has not expired.
+    pub fn get(&self, token: &str) -> Option<ActiveResponse> {
+        let key = key(token);
+        let now = Utc::now().timestamp();
+        let mut entries = self.entries.lock().unwrap();
+        let resp = match entries.get(&key) {
+            Some(entry) if entry.expires_at > now => Some(entry.resp.clone()),
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        };
+        match resp {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        resp
+    }
+
+    /// Cache an active introspection result of the token.
+    /// Inactive results are never cached.
+    pub fn insert(&self, token: &str, resp: &ActiveResponse) {
+        if !self.enabled() || !resp.active {
+            return;
+        }
+        let now = Utc::now().timestamp();
+        let expires_at = match resp.exp {
+            Some(exp) => (now + self.ttl).min(exp),
+            None => now + self.ttl,
+        };
+        if expires_at <= now {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.retain(|_, entry| entry.expires_at > now);
+        }
+        if entries.len() >= self.capacity {
+            // Still full, evict the entry which expires first
+            if let Some(key) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires_at)
+                .map(|(key, _)| *key)
+            {
+                entries.remove(&key);
+            }
+        }
+        entries.insert(
+            key(token),
+            Entry {
+                resp: resp.clone(),
+                expires_at,
+            },
+        );
+    }
+
+    /// Forget the cached introspection result of the token.
+    pub fn remove(&self, token: &str) {
+        self.entries.lock().unwrap().remove(&key(token));
+    }
+
+    /// Return (hits, misses) since startup.
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Tokens revoked by logout, rejected at the gateway until they expire.
+#[derive(Default)]
+pub struct RevokedTokens {
+    entries: Mutex<HashMap<[u8; 32], i64>>,
+}
+
+impl RevokedTokens {
+    /// Revoke the token until `expires_at`, expired revocations are purged meanwhile.
+    pub fn revoke(&self, token: &str, expires_at: i64) {
+        let now = Utc::now().timestamp();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, expires_at| *expires_at > now);
+        if expires_at > now {
+            entries.insert(key(token), expires_at);
+        }
+    }
+
+    pub fn contains(&self, token: &str) -> bool {
+        match self.entries.lock().unwrap().get(&key(token)) {
+            Some(expires_at) => *expires_at > Utc::now().timestamp(),
+            None => false,
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    /// Failures are counted since then, or the user is locked until then once too many
+    until: i64,
+}
+
+/// Results of basic authentication. Verified credentials are kept for a short while,
+/// and users are locked out after repeated failures to stop password guessing.
+pub struct BasicAuthCache {
+    verified: Mutex<HashMap<[u8; 32], (CasdoorUser, String, i64)>>,
+    failures: Mutex<HashMap<String, Failures>>,
+    ttl: i64,
+    max_failures: u32,
+    lockout: i64,
+    capacity: usize,
+}
+
+impl BasicAuthCache {
+    pub fn new(ttl: u64, max_failures: u32, lockout: u64, capacity: usize) -> Self {
+        Self {
+            verified: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+            ttl: ttl as i64,
+            max_failures,
+            lockout: lockout as i64,
+            capacity,
+        }
+    }
+
+    fn key(username: &str, password: &str) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(username.as_bytes())
+            .chain_update([0])
+            .chain_update(password.as_bytes())
+            .finalize()
+            .into()
+    }
+
+    /// Get the user and access token of credentials verified recently.
+    pub fn get(&self, username: &str, password: &str) -> Option<(CasdoorUser, String)> {
+        let now = Utc::now().timestamp();
+        match self.verified.lock().unwrap().get(&Self::key(username, password)) {
+            Some((user, token, expires_at)) if *expires_at > now => {
+                Some((user.clone(), token.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Remember the verified credentials and forget previous failures of the user.
+    pub fn succeed(&self, username: &str, password: &str, user: &CasdoorUser, token: &str) {
+        self.failures.lock().unwrap().remove(username);
+        if self.ttl <= 0 {
+            return;
+        }
+        let now = Utc::now().timestamp();
+        let mut verified = self.verified.lock().unwrap();
+        if verified.len() >= self.capacity {
+            verified.retain(|_, (_, _, expires_at)| *expires_at > now);
+            if verified.len() >= self.capacity {
+                return;
+            }
+        }
+        verified.insert(
+            Self::key(username, password),
+            (user.clone(), token.to_string(), now + self.ttl),
+        );
+    }
+
+    /// Whether the user is locked out by too many failures.
+    pub fn locked(&self, username: &str) -> bool {
+        let now = Utc::now().timestamp();
+        match self.failures.lock().unwrap().get(username) {
+            Some(failures) => failures.count >= self.max_failures && failures.until > now,
+            None => false,
+        }
+    }
+
+    /// Count a failure of the user, return true if the user is locked out now.
+    /// Failures are counted within the lockout period. When full, failures of an unlocked
+    /// user expiring first are forgotten, locked out users are only evicted if all are locked.
+    pub fn fail(&self, username: &str) -> bool {
+        let now = Utc::now().timestamp();
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= self.capacity && !failures.contains_key(username) {
+            failures.retain(|_, failures| failures.until > now);
+            if failures.len() >= self.capacity {
+                if let Some(evicted) = failures
+                    .iter()
+                    .min_by_key(|(_, failures)| (failures.count >= self.max_failures, failures.until))
+                    .map(|(username, _)| username.clone())
+                {
+                    failures.remove(&evicted);
+                }
+            }
+        }
+        let entry = failures.entry(username.to_string()).or_insert(Failures {
+            count: 0,
+            until: now + self.lockout,
+        });
+        if entry.until <= now {
+            *entry = Failures {
+                count: 0,
+                until: now + self.lockout,
+            };
+        }
+        entry.count += 1;
+        if entry.count >= self.max_failures {
+            entry.until = now + self.lockout;
+            return true;
+        }
+        false
+    }
+}
+
+/// Outcome of remembering a proof.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Seen {
+    New,
+    /// The proof has been used before
+    Replayed,
+    /// The key has too many live proofs, only proofs of this key are refused
+    KeyFull,
+}
+
+#[derive(Default)]
+struct Proofs {
+    by_key: HashMap<String, HashMap<[u8; 32], i64>>,
+    len: usize,
+}
+
+/// Proofs seen recently, to refuse replays until they are too old to be accepted anyway.
+/// Each key may only hold `per_key` live proofs, so one client cannot crowd out the others.
+/// Once the whole cache is full, the proof expiring first is forgotten instead of refusing
+/// every new proof.
+pub struct ReplayCache {
+    proofs: Mutex<Proofs>,
+    capacity: usize,
+    per_key: usize,
+}
+
+impl ReplayCache {
+    pub fn new(capacity: usize, per_key: usize) -> Self {
+        Self {
+            proofs: Mutex::new(Proofs::default()),
+            capacity,
+            per_key,
+        }
+    }
+
+    /// Remember the proof `id` of the key until `expires_at`.
+    pub fn insert(&self, key_id: &str, id: &str, expires_at: i64) -> Seen {
+        let now = Utc::now().timestamp();
+        let mut proofs = self.proofs.lock().unwrap();
+        let proofs = &mut *proofs;
+        let seen = proofs.by_key.entry(key_id.to_string()).or_default();
+        let before = seen.len();
+        seen.retain(|_, expires_at| *expires_at > now);
+        proofs.len -= before - seen.len();
+        if seen.contains_key(&key(id)) {
+            return Seen::Replayed;
+        }
+        if seen.len() >= self.per_key {
+            return Seen::KeyFull;
+        }
+        seen.insert(key(id), expires_at);
+        proofs.len += 1;
+
+        if proofs.len > self.capacity {
+            let mut len = 0;
+            for seen in proofs.by_key.values_mut() {
+                seen.retain(|_, expires_at| *expires_at > now);
+                len += seen.len();
+            }
+            proofs.by_key.retain(|_, seen| !seen.is_empty());
+            proofs.len = len;
+        }
+        if proofs.len > self.capacity {
+            let oldest = proofs
+                .by_key
+                .iter()
+                .flat_map(|(key_id, seen)| seen.iter().map(move |(id, expires_at)| (*expires_at, key_id, *id)))
+                .min()
+                .map(|(_, key_id, id)| (key_id.clone(), id));
+            if let Some((key_id, id)) = oldest {
+                if let Some(seen) = proofs.by_key.get_mut(&key_id) {
+                    seen.remove(&id);
+                }
+                proofs.len -= 1;
+            }
+        }
+        Seen::New
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failures_are_bounded() {
+        let cache = BasicAuthCache::new(60, 2, 300, 3);
+        assert!(!cache.fail("alice"));
+        assert!(cache.fail("alice"));
+        for username in ["bob", "carol", "dave", "erin", "frank"] {
+            assert!(!cache.fail(username));
+        }
+        assert_eq!(cache.failures.lock().unwrap().len(), 3);
+        // Spraying usernames does not lift the lockout
+        assert!(cache.locked("alice"));
+        assert!(cache.fail("frank"));
+        assert!(cache.locked("frank"));
+    }
+
+    #[test]
+    fn replay_is_refused() {
+        let cache = ReplayCache::new(10, 10);
+        let exp = Utc::now().timestamp() + 60;
+        assert_eq!(cache.insert("k", "a", exp), Seen::New);
+        assert_eq!(cache.insert("k", "a", exp), Seen::Replayed);
+        // The same id of another key is another proof
+        assert_eq!(cache.insert("other", "a", exp), Seen::New);
+    }
+
+    #[test]
+    fn full_key_does_not_block_others() {
+        let cache = ReplayCache::new(100, 2);
+        let exp = Utc::now().timestamp() + 60;
+        assert_eq!(cache.insert("k", "a", exp), Seen::New);
+        assert_eq!(cache.insert("k", "b", exp), Seen::New);
+        assert_eq!(cache.insert("k", "c", exp), Seen::KeyFull);
+        assert_eq!(cache.insert("other", "c", exp), Seen::New);
+        // Expired proofs free the key
+        let cache = ReplayCache::new(100, 1);
+        assert_eq!(cache.insert("k", "a", Utc::now().timestamp() - 1), Seen::New);
+        assert_eq!(cache.insert("k", "b", exp), Seen::New);
+    }
+
+    #[test]
+    fn full_cache_forgets_the_proof_expiring_first() {
+        let cache = ReplayCache::new(2, 10);
+        let now = Utc::now().timestamp();
+        assert_eq!(cache.insert("k1", "a", now + 10), Seen::New);
+        assert_eq!(cache.insert("k2", "b", now + 60), Seen::New);
+        assert_eq!(cache.insert("k3", "c", now + 30), Seen::New);
+        assert_eq!(cache.insert("k2", "b", now + 60), Seen::Replayed);
+        assert_eq!(cache.insert("k3", "c", now + 30), Seen::Replayed);
+        assert_eq!(cache.insert("k1", "a", now + 10), Seen::New);
+    }
+}
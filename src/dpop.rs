@@ -0,0 +1,276 @@
+use std::collections::HashSet;
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+/// A DPoP proof (RFC 9449) verified against the request and the access token.
+#[derive(Debug)]
+pub struct Proof {
+    pub jti: String,
+    pub iat: i64,
+    /// JWK SHA-256 thumbprint of the proof key
+    pub jkt: String,
+}
+
+/// The request the proof is expected to be made for.
+pub struct Request<'a> {
+    pub method: &'a str,
+    pub proto: Option<&'a str>,
+    pub host: Option<&'a str>,
+    pub path: &'a str,
+}
+
+fn b64(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// JWK SHA-256 thumbprint (RFC 7638) of a public key, with only the required members.
+pub fn thumbprint(jwk: &Value) -> Result<String, String> {
+    let member = |name: &str| {
+        jwk.get(name)
+            .and_then(Value::as_str)
+            .ok_or_else(|| format!("DPoP proof key has no \"{}\"", name))
+    };
+    // serde_json keeps members sorted, as the canonical form requires
+    let canonical = match member("kty")? {
+        "RSA" => json!({ "e": member("e")?, "kty": "RSA", "n": member("n")? }),
+        "EC" => json!({ "crv": member("crv")?, "kty": "EC", "x": member("x")?, "y": member("y")? }),
+        kty => return Err(format!("Unsupported DPoP proof key type {}", kty)),
+    };
+    Ok(b64(&Sha256::digest(canonical.to_string().as_bytes())))
+}
+
+fn decoding_key(jwk: &Value, alg: Algorithm) -> Result<DecodingKey, String> {
+    let member = |name: &str| jwk.get(name).and_then(Value::as_str).unwrap_or_default();
+    match (member("kty"), alg) {
+        ("RSA", Algorithm::RS256 | Algorithm::PS256) => {
+            DecodingKey::from_rsa_components(member("n"), member("e"))
+                .map_err(|err| format!("Invalid DPoP proof key: {}", err))
+        }
+        ("EC", Algorithm::ES256) if member("crv") == "P-256" => {
+            let coordinate = |name: &str| {
+                base64::decode_config(member(name), base64::URL_SAFE_NO_PAD)
+                    .map_err(|err| format!("Invalid DPoP proof key: {}", err))
+            };
+            // Uncompressed point
+            let point = [vec![4], coordinate("x")?, coordinate("y")?].concat();
+            Ok(DecodingKey::from_ec_der(&point))
+        }
+        (kty, alg) => Err(format!("DPoP proof key {} does not match algorithm {:?}", kty, alg)),
+    }
+}
+
+/// Whether `htu` is the url of the request, ignoring its query and fragment.
+/// Only the path is compared if the proxy does not forward the host.
+fn htu_matches(htu: &str, req: &Request) -> bool {
+    let htu = match Url::parse(htu) {
+        Ok(htu) => htu,
+        Err(_) => return false,
+    };
+    if htu.path() != req.path {
+        return false;
+    }
+    match req.host {
+        Some(host) => {
+            let htu_host = match htu.port() {
+                Some(port) => format!("{}:{}", htu.host_str().unwrap_or_default(), port),
+                None => htu.host_str().unwrap_or_default().to_string(),
+            };
+            htu.scheme() == req.proto.unwrap_or("https") && htu_host.eq_ignore_ascii_case(host)
+        }
+        None => true,
+    }
+}
+
+/// Verify the proof of possession sent with the access token.
+/// Proofs are accepted `lifetime` seconds around their `iat`.
+pub fn verify(proof: &str, token: &str, req: &Request, now: i64, lifetime: i64) -> Result<Proof, String> {
+    let header = proof
+        .split('.')
+        .next()
+        .and_then(|header| base64::decode_config(header, base64::URL_SAFE_NO_PAD).ok())
+        .and_then(|header| serde_json::from_slice::<Value>(&header).ok())
+        .ok_or("Malformed DPoP proof header")?;
+    if header.get("typ").and_then(Value::as_str) != Some("dpop+jwt") {
+        return Err("DPoP proof is not typed dpop+jwt".to_string());
+    }
+    let alg = header
+        .get("alg")
+        .cloned()
+        .and_then(|alg| serde_json::from_value::<Algorithm>(alg).ok())
+        .filter(|alg| matches!(alg, Algorithm::RS256 | Algorithm::PS256 | Algorithm::ES256))
+        .ok_or("DPoP proof algorithm is not supported")?;
+    let jwk = header.get("jwk").ok_or("DPoP proof has no jwk")?;
+    if jwk.get("d").is_some() {
+        return Err("DPoP proof key must not be a private key".to_string());
+    }
+
+    let mut validation = Validation::new(alg);
+    validation.required_spec_claims = HashSet::new();
+    validation.validate_exp = false;
+    let claims = jsonwebtoken::decode::<Value>(proof, &decoding_key(jwk, alg)?, &validation)
+        .map_err(|err| format!("Invalid DPoP proof: {}", err))?
+        .claims;
+
+    let claim = |name: &str| claims.get(name).and_then(Value::as_str).unwrap_or_default();
+    if claim("jti").is_empty() {
+        return Err("DPoP proof has no jti".to_string());
+    }
+    if !claim("htm").eq_ignore_ascii_case(req.method) {
+        return Err(format!("DPoP proof is made for method {}", claim("htm")));
+    }
+    if !htu_matches(claim("htu"), req) {
+        return Err(format!("DPoP proof is made for {}", claim("htu")));
+    }
+    let iat = claims.get("iat").and_then(Value::as_i64).ok_or("DPoP proof has no iat")?;
+    if (now - iat).abs() > lifetime {
+        return Err(format!("DPoP proof is issued at {}, local time {}", iat, now));
+    }
+    if claim("ath") != b64(&Sha256::digest(token.as_bytes())) {
+        return Err("DPoP proof is not made for this access token".to_string());
+    }
+
+    Ok(Proof {
+        jti: claim("jti").to_string(),
+        iat,
+        jkt: thumbprint(jwk)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+
+    const TOKEN: &str = "access.token.value";
+    const NOW: i64 = 1_700_000_000;
+    const REQ: Request = Request {
+        method: "POST",
+        proto: Some("https"),
+        host: Some("api.example.com"),
+        path: "/payments/1",
+    };
+
+    fn key_pair() -> (EcdsaKeyPair, Value) {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+        let point = pair.public_key().as_ref();
+        let jwk = json!({ "kty": "EC", "crv": "P-256", "x": b64(&point[1..33]), "y": b64(&point[33..65]) });
+        (pair, jwk)
+    }
+
+    fn sign(pair: &EcdsaKeyPair, header: Value, claims: Value) -> String {
+        let input = format!("{}.{}", b64(header.to_string().as_bytes()), b64(claims.to_string().as_bytes()));
+        let signature = pair.sign(&SystemRandom::new(), input.as_bytes()).unwrap();
+        format!("{}.{}", input, b64(signature.as_ref()))
+    }
+
+    fn claims() -> Value {
+        json!({
+            "jti": "proof-1",
+            "htm": "POST",
+            "htu": "https://api.example.com/payments/1?ignored=1",
+            "iat": NOW,
+            "ath": b64(&Sha256::digest(TOKEN.as_bytes())),
+        })
+    }
+
+    fn proof_with(claims: Value) -> (String, Value) {
+        let (pair, jwk) = key_pair();
+        let header = json!({ "typ": "dpop+jwt", "alg": "ES256", "jwk": jwk });
+        (sign(&pair, header, claims), jwk)
+    }
+
+    #[test]
+    fn valid_proof() {
+        let (proof, jwk) = proof_with(claims());
+        let verified = verify(&proof, TOKEN, &REQ, NOW + 30, 60).unwrap();
+        assert_eq!(verified.jti, "proof-1");
+        assert_eq!(verified.iat, NOW);
+        assert_eq!(verified.jkt, thumbprint(&jwk).unwrap());
+        // Only the path is compared without a forwarded host
+        let req = Request { proto: None, host: None, ..REQ };
+        assert!(verify(&proof, TOKEN, &req, NOW, 60).is_ok());
+    }
+
+    #[test]
+    fn request_mismatch() {
+        let mut wrong_htm = claims();
+        wrong_htm["htm"] = json!("GET");
+        let err = verify(&proof_with(wrong_htm).0, TOKEN, &REQ, NOW, 60).unwrap_err();
+        assert!(err.contains("method GET"), "{}", err);
+
+        for htu in [
+            "https://api.example.com/payments/2",
+            "http://api.example.com/payments/1",
+            "https://other.example.com/payments/1",
+            "https://api.example.com:8443/payments/1",
+            "/payments/1",
+        ] {
+            let mut wrong_htu = claims();
+            wrong_htu["htu"] = json!(htu);
+            let err = verify(&proof_with(wrong_htu).0, TOKEN, &REQ, NOW, 60).unwrap_err();
+            assert!(err.contains("is made for"), "{}: {}", htu, err);
+        }
+
+        let (proof, _) = proof_with(claims());
+        let err = verify(&proof, "another.token", &REQ, NOW, 60).unwrap_err();
+        assert!(err.contains("access token"), "{}", err);
+    }
+
+    #[test]
+    fn expired_or_future_iat() {
+        let (proof, _) = proof_with(claims());
+        assert!(verify(&proof, TOKEN, &REQ, NOW + 60, 60).is_ok());
+        assert!(verify(&proof, TOKEN, &REQ, NOW + 61, 60).unwrap_err().contains("issued at"));
+        assert!(verify(&proof, TOKEN, &REQ, NOW - 61, 60).unwrap_err().contains("issued at"));
+    }
+
+    #[test]
+    fn malformed_proofs() {
+        let (pair, jwk) = key_pair();
+        let mut private = jwk.clone();
+        private["d"] = json!("c2VjcmV0");
+        let proof = sign(&pair, json!({ "typ": "dpop+jwt", "alg": "ES256", "jwk": private }), claims());
+        assert!(verify(&proof, TOKEN, &REQ, NOW, 60).unwrap_err().contains("private key"));
+
+        let proof = sign(&pair, json!({ "typ": "JWT", "alg": "ES256", "jwk": jwk }), claims());
+        assert!(verify(&proof, TOKEN, &REQ, NOW, 60).unwrap_err().contains("dpop+jwt"));
+
+        let proof = sign(&pair, json!({ "typ": "dpop+jwt", "alg": "HS256", "jwk": jwk }), claims());
+        assert!(verify(&proof, TOKEN, &REQ, NOW, 60).unwrap_err().contains("algorithm"));
+
+        // Signed by another key than the one in the header
+        let (other, _) = key_pair();
+        let proof = sign(&other, json!({ "typ": "dpop+jwt", "alg": "ES256", "jwk": jwk }), claims());
+        assert!(verify(&proof, TOKEN, &REQ, NOW, 60).unwrap_err().starts_with("Invalid DPoP proof"));
+
+        let mut no_jti = claims();
+        no_jti.as_object_mut().unwrap().remove("jti");
+        assert!(verify(&proof_with(no_jti).0, TOKEN, &REQ, NOW, 60).unwrap_err().contains("jti"));
+    }
+
+    #[test]
+    fn thumbprint_of_keys() {
+        // RFC 7638 section 3.1
+        let rsa = json!({
+            "kty": "RSA",
+            "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+            "e": "AQAB",
+            "alg": "RS256",
+            "kid": "2011-04-29",
+        });
+        assert_eq!(thumbprint(&rsa).unwrap(), "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs");
+
+        let (_, jwk) = key_pair();
+        let mut extra = jwk.clone();
+        extra["kid"] = json!("ignored");
+        assert_eq!(thumbprint(&extra).unwrap(), thumbprint(&jwk).unwrap());
+        assert!(thumbprint(&json!({ "kty": "oct", "k": "c2VjcmV0" })).is_err());
+        assert!(thumbprint(&json!({ "kty": "EC", "crv": "P-256", "x": "AA" })).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use jsonwebtoken::Algorithm;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 
@@ -17,6 +18,30 @@ pub(crate) struct CasbinRule {
     pub v5: String,
 }
 
+/// Cheap fingerprint of the policy table, used to detect policy changes.
+#[cfg(feature = "builtin-casbin")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromRow)]
+pub struct PolicyVersion {
+    pub count: i64,
+    pub checksum: u64,
+}
+
+/// An api key tied to a casbin subject, its secret is only stored hashed.
+#[cfg(feature = "builtin-casbin")]
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct ApiKey {
+    pub id: i64,
+    pub subject: String,
+    pub name: String,
+    /// Path prefix the key is restricted to
+    pub path_scope: Option<String>,
+    /// Comma separated methods the key is restricted to
+    pub method_scope: Option<String>,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+    pub created_at: i64,
+}
+
 #[cfg(feature = "builtin-casbin")]
 #[derive(Debug)]
 pub(crate) struct NewCasbinRule<'a> {
@@ -29,20 +54,229 @@ pub(crate) struct NewCasbinRule<'a> {
     pub v5: &'a str,
 }
 
+/// Where an access token is taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TokenSource {
+    /// `Authorization: Bearer <token>`
+    #[serde(rename = "header")]
+    Header,
+    /// The cookie named `token_cookie_name`
+    #[serde(rename = "cookie")]
+    Cookie,
+    /// The `access_token` query parameter of `X-Forwarded-Uri`
+    #[serde(rename = "query")]
+    Query,
+    /// `Sec-WebSocket-Protocol: access_token, <token>`
+    #[serde(rename = "websocket")]
+    WebSocket,
+}
+
+/// Step normalizing the request path before it is matched against policies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PathStep {
+    /// Decode percent-encoded characters except `/`, `\`, `%`, `?`, `#` and controls
+    #[serde(rename = "decode")]
+    Decode,
+    /// Collapse repeated slashes
+    #[serde(rename = "merge-slashes")]
+    MergeSlashes,
+    /// Resolve `.` and `..` segments
+    #[serde(rename = "resolve-dots")]
+    ResolveDots,
+    /// Remove the trailing slash
+    #[serde(rename = "strip-trailing-slash")]
+    StripTrailingSlash,
+    /// Lowercase ASCII letters
+    #[serde(rename = "lowercase")]
+    Lowercase,
+}
+
+/// Field of the client certificate naming the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum CertField {
+    /// Common name of the subject
+    #[default]
+    #[serde(rename = "cn")]
+    Cn,
+    /// The whole subject DN
+    #[serde(rename = "dn")]
+    Dn,
+    /// First DNS name of the SAN
+    #[serde(rename = "san-dns")]
+    SanDns,
+    /// First email of the SAN
+    #[serde(rename = "san-email")]
+    SanEmail,
+    /// First URI of the SAN
+    #[serde(rename = "san-uri")]
+    SanUri,
+}
+
+/// How an access token is authenticated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum AuthStrategy {
+    /// Ask casdoor introspection api for every request
+    #[default]
+    #[serde(rename = "introspect")]
+    Introspect,
+    /// Only verify the signature, `exp` and `nbf` of the token locally
+    #[serde(rename = "local")]
+    Local,
+    /// Verify the token locally and recheck it with casdoor every `introspect_interval` seconds
+    #[serde(rename = "local+periodic-introspect")]
+    LocalPeriodicIntrospect,
+}
+
+/// Kind of the identity provider.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum ProviderKind {
+    #[default]
+    #[serde(rename = "casdoor")]
+    Casdoor,
+    /// Any standard OpenID Connect provider
+    #[serde(rename = "oidc")]
+    Oidc,
+}
+
+/// Query parameters enforced along with the paths under the prefix.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QueryParamsConfig {
+    pub path: String,
+    pub params: Vec<String>,
+}
+
+/// How requests of a route are checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum RouteClass {
+    /// Pass without credentials as the anonymous subject
+    #[serde(rename = "public")]
+    Public,
+    /// Need valid credentials but no casbin policy
+    #[serde(rename = "authenticated")]
+    Authenticated,
+    /// Need valid credentials and a casbin policy allowing the request
+    #[default]
+    #[serde(rename = "authorized")]
+    Authorized,
+}
+
+/// Requests matched by host, path and method belong to the route class.
+/// `*` in `host` and `path` matches any characters.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RouteConfig {
+    pub class: RouteClass,
+    pub host: Option<String>,
+    pub path: String,
+    pub methods: Option<Vec<String>>,
+}
+
+/// An identity provider whose tokens are accepted.
+/// The top-level configuration forms the default issuer,
+/// others are listed in `issuers` and chosen by the token's `iss` claim.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IssuerConfig {
+    pub iss: Option<String>,
+    pub provider: Option<ProviderKind>,
+    pub discovery: Option<bool>,
+    pub username_claim: Option<String>,
+    pub endpoint: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub jwt_pub_key: Option<String>,
+    pub jwks_url: Option<String>,
+    pub jwt_audiences: Option<Vec<String>>,
+    pub service_clients: Option<Vec<String>>,
+    pub org_name: String,
+    #[cfg(not(feature = "builtin-casbin"))]
+    pub permission_name: String,
+    pub subject_prefix: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     pub address: String,
     pub port: u16,
+    pub provider: Option<ProviderKind>,
+    pub discovery: Option<bool>,
+    pub username_claim: Option<String>,
     pub endpoint: String,
     pub client_id: String,
     pub client_secret: String,
-    pub jwt_pub_key: String,
+    pub jwt_pub_key: Option<String>,
+    pub cert_expiry_warning_days: Option<i64>,
+    pub jwt_issuers: Option<Vec<String>>,
+    pub jwt_audiences: Option<Vec<String>>,
+    pub service_clients: Option<Vec<String>>,
+    pub jwt_algorithms: Option<Vec<Algorithm>>,
+    pub jwt_leeway: Option<u64>,
+    pub jwks_url: Option<String>,
+    pub jwks_refresh_interval: Option<u64>,
     pub org_name: String,
     pub app_name: Option<String>,
+    pub subject_prefix: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub login_scope: Option<String>,
+    pub allowed_return_hosts: Option<Vec<String>>,
+    pub login_url: Option<String>,
+    pub token_sources: Option<Vec<TokenSource>>,
+    pub token_cookie_name: Option<String>,
+    pub dpop_required_paths: Option<Vec<String>>,
+    pub dpop_proof_lifetime: Option<u64>,
+    pub dpop_max_proofs_per_key: Option<usize>,
+    pub basic_auth: Option<bool>,
+    pub basic_auth_cache_ttl: Option<u64>,
+    pub basic_auth_max_failures: Option<u32>,
+    pub basic_auth_lockout: Option<u64>,
+    pub mtls_header: Option<String>,
+    pub mtls_trusted_proxies: Option<Vec<std::net::IpAddr>>,
+    pub mtls_identity: Option<CertField>,
+    pub mtls_subject_prefix: Option<String>,
+    pub internal_jwt_key: Option<String>,
+    pub internal_jwt_header: Option<String>,
+    pub internal_jwt_issuer: Option<String>,
+    pub internal_jwt_audience: Option<String>,
+    pub internal_jwt_lifetime: Option<u64>,
+    pub internal_jwt_user_fields: Option<Vec<String>>,
+    /// Identity field to the name of the header forwarding it
+    pub identity_headers: Option<HashMap<String, String>>,
+    pub anonymous_subject: Option<String>,
+    pub anonymous_access: Option<bool>,
+    pub routes: Option<Vec<RouteConfig>>,
+    pub path_normalization: Option<Vec<PathStep>>,
+    pub path_strict: Option<bool>,
+    pub query_params: Option<Vec<QueryParamsConfig>>,
+    pub session_cookie: Option<bool>,
+    pub session_cookie_name: Option<String>,
+    pub session_cookie_domain: Option<String>,
+    pub session_capacity: Option<usize>,
+    pub issuers: Option<Vec<IssuerConfig>>,
+    pub auth_strategy: Option<AuthStrategy>,
+    pub introspect_interval: Option<u64>,
+    pub introspect_cache_ttl: Option<u64>,
+    pub introspect_cache_capacity: Option<usize>,
     #[cfg(not(feature = "builtin-casbin"))]
     pub permission_name: String,
     #[cfg(feature = "builtin-casbin")]
     pub casdoor_db: String,
+    #[cfg(feature = "builtin-casbin")]
+    pub policy_poll_interval: Option<u64>,
+}
+
+/// Registered claims of a token, read before or after verification.
+#[derive(Debug, Default, Deserialize)]
+pub struct RegisteredClaims {
+    pub exp: Option<u64>,
+    pub nbf: Option<u64>,
+    pub iss: Option<String>,
+    pub aud: Option<serde_json::Value>,
+    pub cnf: Option<Confirmation>,
+}
+
+/// Key the token is bound to.
+#[derive(Debug, Default, Deserialize)]
+pub struct Confirmation {
+    /// JWK SHA-256 thumbprint of the DPoP proof key
+    pub jkt: Option<String>,
 }
 
 /// User info struct, defined in the SDK.
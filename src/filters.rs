@@ -1,13 +1,50 @@
 use std::collections::HashMap;
+use warp::http::Method;
 use warp::{Filter, Rejection, Reply};
 
 use crate::handlers;
 
+/// Access token of the request, taken from the configured sources in order.
+/// `method` is the method of the request the token is used for.
+fn token<M>(method: M) -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone
+where
+    M: Filter<Extract = (String,), Error = Rejection> + Clone + Send + Sync,
+{
+    warp::header::optional::<String>("Authorization")
+        .and(warp::cookie::optional::<String>(handlers::token_cookie_name()))
+        .and(warp::header::optional::<String>("X-Forwarded-Uri"))
+        .and(warp::header::optional::<String>("Sec-WebSocket-Protocol"))
+        .and(warp::header::optional::<String>("X-CSRF-Token"))
+        .and(warp::cookie::optional::<String>(handlers::csrf_cookie_name()))
+        .and(method)
+        .map(handlers::extract_token)
+}
+
+/// Method of the request itself.
+fn method() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::method().and_then(|method: Method| async move { Ok::<_, Rejection>(method.to_string()) })
+}
+
+/// Client certificate forwarded by a trusted proxy.
+fn client_cert() -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(handlers::mtls_header_name())
+        .and(warp::addr::remote())
+        .map(handlers::trusted_client_cert)
+}
+
 /// GET /authenticate
 pub fn authenticate() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("authenticate")
         .and(warp::get())
-        .and(warp::header::optional::<String>("Authorization"))
+        .and(token(warp::header::<String>("X-Forwarded-Method")))
+        .and(warp::cookie::optional::<String>(handlers::session_cookie_name()))
+        .and(warp::header::optional::<String>("X-CSRF-Token"))
+        .and(warp::header::optional::<String>("Accept"))
+        .and(warp::header::optional::<String>("X-Forwarded-Host"))
+        .and(warp::header::optional::<String>("X-Forwarded-Proto"))
+        .and(warp::header::optional::<String>("DPoP"))
+        .and(warp::header::optional::<String>("Authorization").map(handlers::basic_credentials))
+        .and(client_cert())
         .and(warp::header::<String>("X-Forwarded-Method"))
         .and(warp::header::<String>("X-Forwarded-Uri"))
         .and_then(handlers::handle_authenticate)
@@ -20,3 +57,57 @@ pub fn login() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
         .and(warp::query::<HashMap<String, String>>())
         .and_then(handlers::handle_login)
 }
+
+/// GET /callback
+pub fn callback() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("callback")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::cookie::optional::<String>(handlers::LOGIN_COOKIE))
+        .and_then(handlers::handle_callback)
+}
+
+/// POST /refresh
+pub fn refresh() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("refresh")
+        .and(warp::post())
+        .and(warp::body::form::<HashMap<String, String>>())
+        .and_then(handlers::handle_refresh)
+}
+
+/// POST /logout
+pub fn logout() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("logout")
+        .and(warp::post())
+        .and(token(method()))
+        .and(warp::cookie::optional::<String>(handlers::session_cookie_name()))
+        .and(warp::header::optional::<String>("X-CSRF-Token"))
+        .and(warp::body::form::<HashMap<String, String>>())
+        .and_then(handlers::handle_logout)
+}
+
+/// POST /api-keys, GET /api-keys and DELETE /api-keys/{id}
+#[cfg(feature = "builtin-casbin")]
+pub fn api_keys() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let create = warp::path!("api-keys")
+        .and(warp::post())
+        .and(token(method()))
+        .and(warp::body::form::<HashMap<String, String>>())
+        .and_then(handlers::handle_create_api_key);
+    let list = warp::path!("api-keys")
+        .and(warp::get())
+        .and(token(method()))
+        .and_then(handlers::handle_list_api_keys);
+    let delete = warp::path!("api-keys" / i64)
+        .and(warp::delete())
+        .and(token(method()))
+        .and_then(handlers::handle_delete_api_key);
+    create.or(list).or(delete)
+}
+
+/// GET /.well-known/jwks.json
+pub fn jwks() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!(".well-known" / "jwks.json")
+        .and(warp::get())
+        .and_then(handlers::handle_jwks)
+}
@@ -1,21 +1,45 @@
 use std::collections::HashMap;
 use std::convert::Infallible;
+use std::net::SocketAddr;
 
 #[cfg(feature = "builtin-casbin")]
-use crate::{ADAPTER, MODEL};
+use crate::adapter::SqlxAdapter;
 #[cfg(feature = "builtin-casbin")]
-use casbin::{CoreApi, Enforcer};
+use crate::api_keys;
+#[cfg(feature = "builtin-casbin")]
+use crate::entity::ApiKey;
+#[cfg(feature = "builtin-casbin")]
+use crate::mtls;
+#[cfg(feature = "builtin-casbin")]
+use crate::{ADAPTER, ENFORCER};
+#[cfg(feature = "builtin-casbin")]
+use casbin::CoreApi;
 
+use chrono::Utc;
+use jsonwebtoken::errors::ErrorKind;
 use jsonwebtoken::{Algorithm, DecodingKey, Validation};
-use log::{debug, error};
-use reqwest::header::HeaderValue;
+use log::{debug, error, info, warn};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use reqwest::header::{HeaderName, HeaderValue};
+use serde_json::{json, Value};
+use url::Url;
 use warp::hyper::StatusCode;
 use warp::reject::Reject;
 use warp::{reject, reply, Rejection, Reply};
 
-use crate::entity::CasdoorUser;
+use crate::entity::{AuthStrategy, CasdoorUser, PathStep, RegisteredClaims, RouteClass, TokenSource};
 use crate::response::{ActiveResponse, TokenResponse};
-use crate::{CLIENT, CONFIG};
+use crate::issuer::{self, Issuer};
+use crate::login;
+use crate::normalize;
+use crate::routes;
+use crate::cache::Seen;
+use crate::dpop;
+use crate::signer::Signer;
+use crate::{
+    BASIC_AUTH_CACHE, CLIENT, CONFIG, CSRF_COOKIE_NAME, DPOP_PROOFS, INTROSPECT_CACHE, ISSUERS, LOGINS, REVOKED_TOKENS,
+    SESSIONS, SIGNER,
+};
 
 #[derive(Debug)]
 struct CustomRejection {
@@ -24,33 +48,125 @@ struct CustomRejection {
 
 impl Reject for CustomRejection {}
 
-/// Parse jwt token to casdoor user entity.
-fn parse_jwt_token(token: &str) -> Result<CasdoorUser, Box<dyn std::error::Error>> {
-    let res = jsonwebtoken::decode::<CasdoorUser>(
-        token,
-        &DecodingKey::from_rsa_pem(CONFIG.jwt_pub_key.as_bytes())?,
-        &Validation::new(Algorithm::RS256),
-    )?;
-    Ok(res.claims)
+/// Parse jwt token issued by the issuer to the user identity.
+/// The signing key is chosen by the token's `kid`.
+/// The error explains why the token is rejected.
+fn parse_jwt_token(issuer: &Issuer, token: &str) -> Result<CasdoorUser, Box<dyn std::error::Error>> {
+    let header = jsonwebtoken::decode_header(token)
+        .map_err(|err| format!("Malformed token header: {}", err))?;
+    if !issuer.validation.algorithms.contains(&header.alg) {
+        return Err(format!(
+            "Token algorithm {:?} is not allowed by {:?}",
+            header.alg, issuer.validation.algorithms
+        )
+        .into());
+    }
+    // Every allowed algorithm must match the key family, so only check the token's own one
+    let mut validation = issuer.validation.clone();
+    validation.algorithms = vec![header.alg];
+
+    let mut last_err = None;
+    for key in issuer.keys.candidates(header.kid.as_deref()) {
+        match jsonwebtoken::decode::<serde_json::Value>(token, &key, &validation) {
+            Ok(res) => return Ok(issuer.identity(res.claims)?),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    match last_err {
+        Some(err) => Err(describe_token_error(issuer, token, header.alg, &err).into()),
+        None => Err(format!("No signing key matches kid {:?}", header.kid).into()),
+    }
+}
+
+/// Until when a refresh token has to be remembered as revoked, None if it is not signed by the issuer.
+/// Refresh tokens are not issued to the audience of access tokens, so only the signature is checked.
+fn refresh_token_expiry(issuer: &Issuer, token: &str) -> Option<i64> {
+    let header = jsonwebtoken::decode_header(token).ok()?;
+    if !issuer.validation.algorithms.contains(&header.alg) {
+        return None;
+    }
+    let mut validation = Validation::new(header.alg);
+    validation.required_spec_claims.clear();
+    validation.validate_exp = false;
+    let claims = issuer
+        .keys
+        .candidates(header.kid.as_deref())
+        .iter()
+        .find_map(|key| jsonwebtoken::decode::<RegisteredClaims>(token, key, &validation).ok())?
+        .claims;
+    // Refresh tokens without exp are remembered for 30 days
+    Some(claims.exp.map_or_else(|| Utc::now().timestamp() + 30 * 86400, |exp| exp as i64))
+}
+
+/// Explain a token validation error.
+/// Time claims are compared with the local clock to help debugging clock drift.
+fn describe_token_error(
+    issuer: &Issuer,
+    token: &str,
+    alg: Algorithm,
+    err: &jsonwebtoken::errors::Error,
+) -> String {
+    let claims = peek_claims(token).unwrap_or_default();
+    let now = jsonwebtoken::get_current_timestamp();
+    let show = |v: Option<String>| v.unwrap_or_else(|| "none".to_string());
+    match err.kind() {
+        ErrorKind::ExpiredSignature => format!(
+            "Token expired at {}, local time {}, leeway {}s",
+            show(claims.exp.map(|exp| exp.to_string())),
+            now,
+            issuer.validation.leeway
+        ),
+        ErrorKind::ImmatureSignature => format!(
+            "Token not valid before {}, local time {}, leeway {}s",
+            show(claims.nbf.map(|nbf| nbf.to_string())),
+            now,
+            issuer.validation.leeway
+        ),
+        ErrorKind::InvalidIssuer => format!(
+            "Token issuer {} is not in {:?}",
+            show(claims.iss),
+            issuer.validation.iss.iter().flatten().collect::<Vec<&String>>()
+        ),
+        ErrorKind::InvalidAudience => format!(
+            "Token audience {} is not in {:?}",
+            show(claims.aud.map(|aud| aud.to_string())),
+            issuer.validation.aud.iter().flatten().collect::<Vec<&String>>()
+        ),
+        ErrorKind::InvalidAlgorithm => {
+            format!("Token algorithm {:?} does not match the signing key", alg)
+        }
+        ErrorKind::InvalidSignature => "Token signature is invalid".to_string(),
+        ErrorKind::MissingRequiredClaim(claim) => format!("Token claim \"{}\" is missing", claim),
+        _ => format!("Token is invalid: {}", err),
+    }
+}
+
+/// Read the registered claims of a token without any validation.
+fn peek_claims(token: &str) -> Option<RegisteredClaims> {
+    let mut validation = Validation::default();
+    validation.insecure_disable_signature_validation();
+    validation.required_spec_claims.clear();
+    validation.validate_exp = false;
+    jsonwebtoken::decode::<RegisteredClaims>(token, &DecodingKey::from_secret(&[]), &validation)
+        .map(|res| res.claims)
+        .ok()
 }
 
 // Authorization
 #[cfg(feature = "builtin-casbin")]
-async fn enforce(sub: &str, path: String, method: String) -> Result<bool, Rejection> {
-    let model = MODEL.get().ok_or(reject::custom(CustomRejection {
-        msg: "Get permission model from memory failed (None Model)".to_string(),
-    }))?;
-    let adapter = ADAPTER.get().ok_or(reject::custom(CustomRejection {
-        msg: "Get permission adapter from memory failed (None Adapter)".to_string(),
-    }))?;
-    let enforcer = Enforcer::new(model.clone(), adapter.clone())
-        .await
-        .map_err(|err| {
-            error!("{}", err);
-            reject::custom(CustomRejection {
-                msg: "Build enforcer from permission model and adapter failed".to_string(),
-            })
-        })?;
+async fn enforce(
+    _issuer: &Issuer,
+    sub: &str,
+    path: String,
+    method: String,
+) -> Result<bool, Rejection> {
+    let enforcer = ENFORCER
+        .get()
+        .ok_or(reject::custom(CustomRejection {
+            msg: "Get enforcer from memory failed (None Enforcer)".to_string(),
+        }))?
+        .read()
+        .await;
 
     let res = enforcer
         .enforce((sub, path, method.to_lowercase()))
@@ -66,17 +182,22 @@ async fn enforce(sub: &str, path: String, method: String) -> Result<bool, Reject
 
 // Authorization
 #[cfg(not(feature = "builtin-casbin"))]
-async fn enforce(token: &str, path: String, method: String) -> Result<bool, Rejection> {
+async fn enforce(
+    issuer: &Issuer,
+    token: &str,
+    path: String,
+    method: String,
+) -> Result<bool, Rejection> {
     let mut body = HashMap::new();
     body.insert(
         "id",
-        format!("{}/{}", CONFIG.org_name, CONFIG.permission_name),
+        format!("{}/{}", issuer.conf.org_name, issuer.conf.permission_name),
     );
     body.insert("v1", path);
     body.insert("v2", method.to_lowercase());
 
     let resp = CLIENT
-        .post(format!("{}/api/enforce", CONFIG.endpoint))
+        .post(format!("{}/api/enforce", issuer.conf.endpoint))
         .json(&body)
         .header("Content-Type", "text/plain")
         .header("Authorization", format!("Bearer {}", token))
@@ -108,106 +229,1250 @@ async fn enforce(token: &str, path: String, method: String) -> Result<bool, Reje
     Ok(res)
 }
 
-/// Send code to casdoor api and return access_token if successful.
-/// Return FORBIDDEN if login failed.
-pub async fn handle_login(query: HashMap<String, String>) -> Result<impl Reply, Rejection> {
-    let code = query.get("code").ok_or(reject::reject())?;
+/// Ask the casdoor of the issuer whether the token is active.
+/// Active results are cached until the earlier of the configured ttl and the token's expiry.
+async fn introspect(issuer: &Issuer, token: &str) -> Result<ActiveResponse, Rejection> {
+    if INTROSPECT_CACHE.enabled() {
+        if let Some(resp) = INTROSPECT_CACHE.get(token) {
+            let (hits, misses) = INTROSPECT_CACHE.stats();
+            debug!("Introspection cache hit (hits: {}, misses: {})", hits, misses);
+            return Ok(resp);
+        }
+    }
+
+    let url = issuer.endpoints().introspection.ok_or(reject::custom(CustomRejection {
+        msg: format!("Issuer {} has no introspection endpoint", issuer.name()),
+    }))?;
     let resp = CLIENT
-        .post(format!("{}/api/login/oauth/access_token?grant_type=authorization_code&client_id={}&client_secret={}&code={}", CONFIG.endpoint, CONFIG.client_id, CONFIG.client_secret, code))
+        .post(&url)
+        .basic_auth(&issuer.conf.client_id, Some(&issuer.conf.client_secret))
+        .form(&[("token", token), ("token_type_hint", "access_token")])
         .send()
         .await
         .map_err(|err| {
             error!("{}", err);
-            reject::custom(CustomRejection { 
-                msg: "Request for casdoor api \"/api/login/oauth/access_token\" failed".to_string()
+            reject::custom(CustomRejection {
+                msg: format!("Request for introspection endpoint \"{}\" failed", url)
             })
         })?
-        .json::<TokenResponse>()
+        .json::<ActiveResponse>()
         .await
-        .map_err(|_| reject::reject())?;
-    let token = resp.access_token;
-    let mut resp = HashMap::new();
-    resp.insert("token", token.clone());
-    match parse_jwt_token(&token) {
-        Ok(_) => Ok(reply::json(&resp)),
-        Err(_) => Err(reject::reject()),
+        .map_err(|err| {
+            error!("{}", err);
+            reject::custom(CustomRejection {
+                msg: format!("Deserialize response from introspection endpoint \"{}\" failed", url)
+            })
+        })?;
+
+    INTROSPECT_CACHE.insert(token, &resp);
+    Ok(resp)
+}
+
+/// Authenticate the token with the configured strategy, using the issuer chosen by its `iss`.
+/// Return the issuer and the user it belongs to, or the reason why the token is not valid.
+async fn authenticate(
+    token: &str,
+) -> Result<Result<(&'static Issuer, CasdoorUser), String>, Rejection> {
+    if REVOKED_TOKENS.contains(token) {
+        return Ok(Err("Token has been revoked".to_string()));
+    }
+    let iss = peek_claims(token).and_then(|claims| claims.iss);
+    let issuer = issuer::select(&ISSUERS, iss.as_deref());
+    let strategy = CONFIG.auth_strategy.unwrap_or_default();
+
+    if strategy != AuthStrategy::Introspect {
+        let user = match parse_jwt_token(issuer, token) {
+            Ok(user) => user,
+            Err(err) => return Ok(Err(err.to_string())),
+        };
+        if strategy == AuthStrategy::LocalPeriodicIntrospect
+            && !introspect(issuer, token).await?.active
+        {
+            return Ok(Err("Token is not active".to_string()));
+        }
+        return Ok(Ok((issuer, user)));
+    }
+
+    let resp = introspect(issuer, token).await?;
+    debug!("{:#?}", resp);
+    if !resp.active {
+        return Ok(Err("Token is not active".to_string()));
+    }
+
+    // User's organization and name should not be changed by updating profile.
+    // So that a valid access_token can always get the valid id (owner/name).
+    // An active token is still rejected if it fails the local checks of iss, aud, alg
+    // and expiry, or is issued to a client not listed in service_clients.
+    match parse_jwt_token(issuer, token) {
+        Ok(user) => Ok(Ok((issuer, user))),
+        Err(err) => Ok(Err(err.to_string())),
     }
 }
 
-/// Every request to microservices behind will be handled in this function. 
-/// The function will do authentication first to confirm the access_token is valid. 
-/// Then it will do authorization using casbin to check the request permission. 
-/// (sub, obj, act) <-> (owner/name, request path, lowercase request method)
-pub async fn handle_authenticate(
-    token: Option<String>,
+/// Name of the header carrying the client certificate.
+pub fn mtls_header_name() -> &'static str {
+    CONFIG.mtls_header.as_deref().unwrap_or("X-Client-Cert")
+}
+
+/// Client certificate of the request, only trusted if `mtls_header` is configured
+/// and the request comes from one of `mtls_trusted_proxies` if they are configured.
+pub fn trusted_client_cert(cert: Option<String>, remote: Option<SocketAddr>) -> Option<String> {
+    CONFIG.mtls_header.as_ref()?;
+    let cert = cert.filter(|cert| !cert.is_empty())?;
+    if let Some(proxies) = &CONFIG.mtls_trusted_proxies {
+        let trusted = remote.is_some_and(|remote| proxies.contains(&remote.ip()));
+        if !trusted {
+            warn!("Ignore client certificate header from untrusted {:?}", remote);
+            return None;
+        }
+    }
+    Some(cert)
+}
+
+/// Name of the cookie holding the access token.
+pub fn token_cookie_name() -> &'static str {
+    CONFIG.token_cookie_name.as_deref().unwrap_or("access_token")
+}
+
+/// Token of the `Authorization` header with the `Bearer` or `DPoP` scheme.
+/// A header without scheme is taken as the token for compatibility.
+fn bearer_token(authorization: &str) -> Option<String> {
+    match authorization.trim().split_once(' ') {
+        // DPoP-bound tokens are checked by their `cnf` whatever the scheme
+        Some((scheme, token))
+            if scheme.eq_ignore_ascii_case("Bearer") || scheme.eq_ignore_ascii_case("DPoP") =>
+        {
+            Some(token.trim().to_string())
+        }
+        Some(_) => None,
+        None => Some(authorization.trim().to_string()),
+    }
+}
+
+/// Token of the `access_token` query parameter of the uri.
+fn query_token(uri: &str) -> Option<String> {
+    let (_, query) = uri.split_once('?')?;
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == "access_token")
+        .map(|(_, token)| token.into_owned())
+}
+
+/// Token following the `access_token` subprotocol, as browsers can not set headers of WebSockets.
+fn websocket_token(protocols: &str) -> Option<String> {
+    let mut protocols = protocols.split(',').map(str::trim);
+    protocols.find(|protocol| *protocol == "access_token")?;
+    protocols.next().map(str::to_string)
+}
+
+/// Take the access token from the configured sources in order, only `Authorization` by default.
+/// Browsers send the token cookie with cross-site requests as well, so for state-changing methods
+/// the cookie is only taken along with `X-CSRF-Token` matching the cookie `<session_cookie_name>_csrf`.
+pub fn extract_token(
+    authorization: Option<String>,
+    cookie: Option<String>,
+    uri: Option<String>,
+    protocols: Option<String>,
+    csrf: Option<String>,
+    csrf_cookie: Option<String>,
     method: String,
-    path: String,
-) -> Result<impl Reply, Rejection> {
+) -> Option<String> {
+    CONFIG
+        .token_sources
+        .as_deref()
+        .unwrap_or(&[TokenSource::Header])
+        .iter()
+        .find_map(|source| match source {
+            TokenSource::Header => authorization.as_deref().and_then(bearer_token),
+            TokenSource::Cookie => cookie.clone().filter(|_| {
+                let valid = !csrf_required(&method)
+                    || csrf.as_deref().zip(csrf_cookie.as_deref()).is_some_and(|(sent, expected)| {
+                        !expected.is_empty() && login::constant_time_eq(expected, sent)
+                    });
+                if !valid {
+                    info!("Ignore token cookie of {} request without a valid CSRF token", method);
+                }
+                valid
+            }),
+            TokenSource::Query => uri.as_deref().and_then(query_token),
+            TokenSource::WebSocket => protocols.as_deref().and_then(websocket_token),
+        })
+        .filter(|token| !token.is_empty())
+}
 
-    let token = if method == "OPTIONS" {
-        // CORS precheck request
-        return Ok(reply::with_status(reply::reply(), StatusCode::OK).into_response())
-    } else {
-        token.ok_or(reject::reject())?
+/// Whether requests of the method must carry a CSRF token when authenticated by a cookie.
+fn csrf_required(method: &str) -> bool {
+    !matches!(method, "GET" | "HEAD" | "OPTIONS" | "TRACE")
+}
+
+/// Build an UNAUTHORIZED response carrying the reason in `WWW-Authenticate` header.
+fn unauthorized(reason: &str) -> reply::Response {
+    info!("Reject token: {}", reason);
+    challenge("Bearer error=\"invalid_token\"", reason)
+}
+
+/// Build an UNAUTHORIZED response for a missing or invalid DPoP proof.
+fn dpop_unauthorized(reason: &str) -> reply::Response {
+    info!("Reject DPoP proof: {}", reason);
+    challenge("DPoP error=\"invalid_dpop_proof\", algs=\"ES256 RS256 PS256\"", reason)
+}
+
+fn challenge(params: &str, reason: &str) -> reply::Response {
+    let mut response = reply::with_status(reply::reply(), StatusCode::UNAUTHORIZED).into_response();
+    // Keep the header value printable and the quoted string well-formed
+    let description = reason
+        .chars()
+        .map(|c| if c == '"' || c == '\\' || !(' '..='~').contains(&c) { '\'' } else { c })
+        .collect::<String>();
+    if let Ok(value) = HeaderValue::from_str(&format!(
+        "{}, error_description=\"{}\"",
+        params, description
+    )) {
+        response.headers_mut().insert("WWW-Authenticate", value);
+    }
+    response
+}
+
+/// Whether the normalized path only accepts DPoP-bound tokens by `dpop_required_paths`.
+fn dpop_required(path: &str) -> bool {
+    CONFIG
+        .dpop_required_paths
+        .iter()
+        .flatten()
+        .any(|prefix| normalize::under(path, prefix))
+}
+
+/// Check the DPoP proof of the access token.
+/// Tokens bound to a key always need a proof, and required paths only accept bound tokens.
+/// The proof is checked against the path as it is sent, while `required` is decided
+/// on the normalized path matched by policies.
+fn check_dpop(token: &str, proof: Option<&str>, req: &dpop::Request, required: bool) -> Result<(), String> {
+    let jkt = peek_claims(token).and_then(|claims| claims.cnf).and_then(|cnf| cnf.jkt);
+    let (jkt, proof) = match (jkt, proof) {
+        (Some(jkt), Some(proof)) => (jkt, proof),
+        (Some(_), None) => return Err("Token is bound to a key but has no DPoP proof".to_string()),
+        (None, _) if required => return Err(format!("Path {} requires a DPoP-bound token", req.path)),
+        (None, _) => return Ok(()),
     };
 
-    let msg = format!("{{token: {}, method: {}, path: {}}}", token, method, path);
-    debug!("Authenticate inbound request: {}", msg);
+    let now = Utc::now().timestamp();
+    let lifetime = CONFIG.dpop_proof_lifetime.unwrap_or(60) as i64;
+    let proof = dpop::verify(proof, token, req, now, lifetime)?;
+    if proof.jkt != jkt {
+        return Err("DPoP proof key does not match the token".to_string());
+    }
+    match DPOP_PROOFS.insert(&proof.jkt, &proof.jti, proof.iat + lifetime) {
+        Seen::New => Ok(()),
+        Seen::Replayed => Err(format!("DPoP proof {} has been used", proof.jti)),
+        Seen::KeyFull => {
+            warn!("Refuse DPoP proofs of key {} sending too many proofs", proof.jkt);
+            Err("Too many DPoP proofs of the key, retry later".to_string())
+        }
+    }
+}
 
-    // Remove url params
-    let path = path.split('?').collect::<Vec<&str>>().get(0)
-        .ok_or(reject::custom(CustomRejection { msg: "Split path failed".to_string() }))?
-        .to_string();
+fn basic_auth_enabled() -> bool {
+    CONFIG.basic_auth.unwrap_or(false)
+}
 
-    // Authentication
+/// Build an UNAUTHORIZED response asking legacy clients for basic credentials.
+fn basic_challenge(reason: &str) -> reply::Response {
+    info!("Reject basic credentials: {}", reason);
+    let mut response = reply::with_status(reply::reply(), StatusCode::UNAUTHORIZED).into_response();
+    let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", env!("CARGO_PKG_NAME"));
+    if let Ok(value) = HeaderValue::from_str(&challenge) {
+        response.headers_mut().insert("WWW-Authenticate", value);
+    }
+    response
+}
+
+/// Username and password of the `Authorization` header with the `Basic` scheme,
+/// only taken if `basic_auth` is enabled.
+pub fn basic_credentials(authorization: Option<String>) -> Option<(String, String)> {
+    if !basic_auth_enabled() {
+        return None;
+    }
+    let authorization = authorization?;
+    let (scheme, encoded) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Verify the basic credentials by the password grant of the default issuer.
+/// Return the user and its access token, or the reason why the credentials are rejected.
+async fn authenticate_basic(
+    username: &str,
+    password: &str,
+) -> Result<Result<(CasdoorUser, String), String>, Rejection> {
+    if BASIC_AUTH_CACHE.locked(username) {
+        return Ok(Err(format!("User {} is locked out after repeated failures", username)));
+    }
+    if let Some(res) = BASIC_AUTH_CACHE.get(username, password) {
+        return Ok(Ok(res));
+    }
+
+    let issuer = &ISSUERS[0];
+    let grant = [
+        ("grant_type", "password"),
+        ("username", username),
+        ("password", password),
+    ];
+    let user = match request_token(issuer, &grant).await {
+        Ok(resp) => parse_jwt_token(issuer, &resp.access_token)
+            .map(|user| (user, resp.access_token))
+            .map_err(|err| err.to_string()),
+        // The token endpoint is unreachable, which says nothing about the password
+        Err(err) if err.find::<CustomRejection>().is_some() => return Err(err),
+        Err(_) => Err(format!("Invalid password of user {}", username)),
+    };
+    match user {
+        Ok((user, token)) => {
+            BASIC_AUTH_CACHE.succeed(username, password, &user, &token);
+            Ok(Ok((user, token)))
+        }
+        Err(reason) => {
+            if BASIC_AUTH_CACHE.fail(username) {
+                warn!("Lock out user {} after repeated basic authentication failures", username);
+            }
+            Ok(Err(reason))
+        }
+    }
+}
 
-    let resp = CLIENT.post(format!("{}/api/login/oauth/introspect?token={}&token_type_hint=access_token&client_id={}&client_secret={}", CONFIG.endpoint, token, CONFIG.client_id, CONFIG.client_secret))
+/// Request tokens from the token endpoint of the issuer with the grant in the form.
+async fn request_token(issuer: &Issuer, grant: &[(&str, &str)]) -> Result<TokenResponse, Rejection> {
+    let url = issuer.endpoints().token.ok_or(reject::custom(CustomRejection {
+        msg: format!("Issuer {} has no token endpoint", issuer.name()),
+    }))?;
+    let mut form = vec![
+        ("client_id", issuer.conf.client_id.as_str()),
+        ("client_secret", issuer.conf.client_secret.as_str()),
+    ];
+    form.extend_from_slice(grant);
+    CLIENT
+        .post(&url)
+        .form(&form)
         .send()
         .await
         .map_err(|err| {
             error!("{}", err);
-            reject::custom(CustomRejection {
-                msg: "Request for casdoor api \"/api/login/oauth/introspect\" failed".to_string()
+            reject::custom(CustomRejection { 
+                msg: format!("Request for token endpoint \"{}\" failed", url)
             })
         })?
-        .json::<ActiveResponse>()
+        .json::<TokenResponse>()
+        .await
+        .map_err(|_| reject::reject())
+}
+
+/// Exchange the authorization code for tokens at the token endpoint of the issuer.
+/// `pkce` carries the code verifier and redirect uri of a login started by the gateway.
+async fn exchange_code(
+    issuer: &Issuer,
+    code: &str,
+    pkce: Option<(&str, &str)>,
+) -> Result<TokenResponse, Rejection> {
+    let mut grant = vec![("grant_type", "authorization_code"), ("code", code)];
+    if let Some((code_verifier, redirect_uri)) = pkce {
+        grant.push(("code_verifier", code_verifier));
+        grant.push(("redirect_uri", redirect_uri));
+    }
+    request_token(issuer, &grant).await
+}
+
+/// Tokens returned to the client, the refresh token is omitted if the issuer gave none.
+fn token_json(resp: &TokenResponse) -> reply::Response {
+    let mut json = HashMap::new();
+    json.insert("token", resp.access_token.clone());
+    if !resp.refresh_token.is_empty() {
+        json.insert("refresh_token", resp.refresh_token.clone());
+    }
+    reply::json(&json).into_response()
+}
+
+/// Revoke the token at the revocation endpoint of the issuer.
+async fn revoke(issuer: &Issuer, url: &str, token: &str, hint: &str) -> Result<(), Rejection> {
+    let resp = CLIENT
+        .post(url)
+        .basic_auth(&issuer.conf.client_id, Some(&issuer.conf.client_secret))
+        .form(&[("token", token), ("token_type_hint", hint)])
+        .send()
         .await
         .map_err(|err| {
             error!("{}", err);
             reject::custom(CustomRejection {
-                msg: "Deserialize response from casdoor api \"/api/login/oauth/introspect\" failed".to_string()
+                msg: format!("Request for revocation endpoint \"{}\" failed", url)
             })
         })?;
+    if !resp.status().is_success() {
+        return Err(reject::custom(CustomRejection {
+            msg: format!("Revocation endpoint \"{}\" returned {}", url, resp.status()),
+        }));
+    }
+    Ok(())
+}
 
-    debug!("{:#?}", resp);
+/// Check the target to return to after login, preventing open redirects.
+/// Only local paths and urls of `allowed_return_hosts` are accepted.
+fn return_target(target: &str) -> Option<String> {
+    checked_return_target(target, allowed_return_hosts())
+}
 
-    if !resp.active {
-        return Ok(reply::with_status(reply::reply(), StatusCode::UNAUTHORIZED).into_response());
+fn checked_return_target(target: &str, allowed_hosts: &[String]) -> Option<String> {
+    // Browsers drop tabs and newlines from urls and take "\" as "/",
+    // so "/\t/evil.com" would be followed to another host
+    if target
+        .bytes()
+        .any(|c| c.is_ascii_control() || c.is_ascii_whitespace() || c == b'\\')
+    {
+        return None;
     }
+    if target.starts_with('/') {
+        // A local path must resolve to the same origin
+        let base = Url::parse("https://gateway.invalid/").ok()?;
+        let url = base.join(target).ok()?;
+        return (url.origin() == base.origin()).then(|| target.to_string());
+    }
+    let url = Url::parse(target).ok()?;
+    match url.host_str() {
+        Some(host) if matches!(url.scheme(), "http" | "https") => allowed_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+            .then(|| url.to_string()),
+        _ => None,
+    }
+}
 
-    // Authorization
+fn allowed_return_hosts() -> &'static [String] {
+    CONFIG.allowed_return_hosts.as_deref().unwrap_or_default()
+}
 
-    // User's organization and name should not be changed by updating profile.
-    // So that a valid access_token can always get the valid id (owner/name).
-    // Token should be valid when enforce permission control since authentication completed.
-    let user = parse_jwt_token(&token).map_err(|err| {
+fn allowed_return_host(host: &str) -> bool {
+    allowed_return_hosts().iter().any(|allowed| allowed.eq_ignore_ascii_case(host))
+}
+
+/// Build a FOUND response redirecting to the location.
+fn redirect(location: &str) -> Result<reply::Response, Rejection> {
+    let mut response = reply::with_status(reply::reply(), StatusCode::FOUND).into_response();
+    let location = HeaderValue::from_str(location).map_err(|err| {
+        error!("{}", err);
+        reject::custom(CustomRejection { msg: "Add Location header failed".to_string() })
+    })?;
+    response.headers_mut().insert("Location", location);
+    Ok(response)
+}
+
+fn bad_request(msg: &str) -> reply::Response {
+    reply::with_status(reply::html(msg.to_string()), StatusCode::BAD_REQUEST).into_response()
+}
+
+fn sessions_enabled() -> bool {
+    CONFIG.session_cookie.unwrap_or(false)
+}
+
+/// Name of the cookie holding the session id.
+pub fn session_cookie_name() -> &'static str {
+    CONFIG.session_cookie_name.as_deref().unwrap_or("akashic_session")
+}
+
+/// Name of the cookie holding the CSRF token, readable by scripts of the app.
+pub fn csrf_cookie_name() -> &'static str {
+    &CSRF_COOKIE_NAME
+}
+
+/// Name of the cookie holding the state of a pending login.
+pub const LOGIN_COOKIE: &str = "akashic_login";
+
+/// Append a secure cookie to the response, it is removed if `max_age` is 0.
+fn set_cookie(
+    response: &mut reply::Response,
+    name: &str,
+    value: &str,
+    max_age: i64,
+    http_only: bool,
+) -> Result<(), Rejection> {
+    let mut cookie = format!("{}={}; Path=/; Max-Age={}; Secure; SameSite=Lax", name, value, max_age);
+    if let Some(domain) = &CONFIG.session_cookie_domain {
+        cookie.push_str(&format!("; Domain={}", domain));
+    }
+    if http_only {
+        cookie.push_str("; HttpOnly");
+    }
+    let cookie = HeaderValue::from_str(&cookie).map_err(|err| {
+        error!("{}", err);
+        reject::custom(CustomRejection { msg: "Add Set-Cookie header failed".to_string() })
+    })?;
+    response.headers_mut().append("Set-Cookie", cookie);
+    Ok(())
+}
+
+/// Access token of the session in the cookie.
+/// Requests with state-changing methods must carry the CSRF token of the session.
+/// Return the response rejecting the request otherwise.
+fn session_token(id: &str, method: &str, csrf: Option<&str>) -> Result<String, Box<reply::Response>> {
+    let session = SESSIONS
+        .get(id)
+        .ok_or_else(|| Box::new(unauthorized("Session is invalid or expired")))?;
+    if csrf_required(method) && !csrf.is_some_and(|csrf| login::verify_csrf(&session, csrf)) {
+        info!("Reject {} request of session without a valid CSRF token", method);
+        return Err(Box::new(
+            reply::with_status(reply::reply(), StatusCode::FORBIDDEN).into_response(),
+        ));
+    }
+    Ok(session.access_token)
+}
+
+/// Start a browser login by redirecting to the authorization endpoint of the default issuer,
+/// with a `state` and a PKCE code challenge. The user is sent back to `return_to` afterwards.
+/// For compatibility, a `code` obtained elsewhere is exchanged directly and
+/// the access_token is returned if successful, or FORBIDDEN if login failed.
+pub async fn handle_login(query: HashMap<String, String>) -> Result<reply::Response, Rejection> {
+    let issuer = &ISSUERS[0];
+
+    if let Some(code) = query.get("code") {
+        let resp = exchange_code(issuer, code, None).await?;
+        return match parse_jwt_token(issuer, &resp.access_token) {
+            Ok(_) => Ok(token_json(&resp)),
+            Err(_) => Err(reject::reject()),
+        };
+    }
+
+    let redirect_uri = CONFIG.redirect_uri.as_ref().ok_or(reject::custom(CustomRejection {
+        msg: "Browser login requires redirect_uri to be configured".to_string(),
+    }))?;
+    let return_to = match query.get("return_to") {
+        Some(target) => match return_target(target) {
+            Some(target) => target,
+            None => return Ok(bad_request("Return target is not allowed")),
+        },
+        None => "/".to_string(),
+    };
+    let authorization = issuer.endpoints().authorization.ok_or(reject::custom(CustomRejection {
+        msg: format!("Issuer {} has no authorization endpoint", issuer.name()),
+    }))?;
+    let (state, code_verifier) = LOGINS.start(return_to);
+
+    let url = Url::parse_with_params(
+        &authorization,
+        &[
+            ("client_id", issuer.conf.client_id.as_str()),
+            ("response_type", "code"),
+            ("redirect_uri", redirect_uri),
+            ("scope", CONFIG.login_scope.as_deref().unwrap_or("openid profile email")),
+            ("state", &state),
+            ("code_challenge", &login::code_challenge(&code_verifier)),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|err| {
         error!("{}", err);
         reject::custom(CustomRejection {
-            msg: "Unexpected token when enforce permission control".to_string(),
+            msg: format!("Invalid authorization endpoint \"{}\"", authorization),
         })
     })?;
-    let sub = format!("{}/{}", user.owner, user.name);
+    // Tie the state to this browser, a callback in another browser is refused
+    let mut response = redirect(url.as_str())?;
+    set_cookie(&mut response, LOGIN_COOKIE, &state, login::LOGIN_TTL as i64, true)?;
+    Ok(response)
+}
+
+/// Finish a browser login started by `/login`.
+/// The `state` is verified against the login cookie of the browser and the code is exchanged
+/// with the PKCE code verifier, then the user is redirected back with the access_token in the url fragment.
+pub async fn handle_callback(
+    query: HashMap<String, String>,
+    login_cookie: Option<String>,
+) -> Result<reply::Response, Rejection> {
+    if let Some(err) = query.get("error") {
+        info!("Login failed: {}", err);
+        return Ok(bad_request("Login failed"));
+    }
+    let (state, code) = match (query.get("state"), query.get("code")) {
+        (Some(state), Some(code)) => (state, code),
+        _ => return Ok(bad_request("Missing state or code")),
+    };
+    if !login_cookie.is_some_and(|cookie| login::constant_time_eq(&cookie, state)) {
+        return Ok(bad_request("Login was not started by this browser"));
+    }
+    let login = match LOGINS.take(state) {
+        Some(login) => login,
+        None => return Ok(bad_request("Invalid or expired login state")),
+    };
+
+    let issuer = &ISSUERS[0];
+    let redirect_uri = CONFIG.redirect_uri.as_deref().unwrap_or_default();
+    let resp = exchange_code(issuer, code, Some((&login.code_verifier, redirect_uri))).await?;
+    if let Err(err) = parse_jwt_token(issuer, &resp.access_token) {
+        info!("Reject token from login: {}", err);
+        return Err(reject::reject());
+    }
+
+    // The fragment is never sent to servers, keep only the one carrying the token
+    let target = login.return_to.split('#').next().unwrap_or("/");
+    if sessions_enabled() {
+        let (id, session) = SESSIONS.create(resp.access_token, resp.expires_in as i64);
+        let mut response = redirect(target)?;
+        set_cookie(&mut response, LOGIN_COOKIE, "", 0, true)?;
+        set_cookie(&mut response, session_cookie_name(), &id, resp.expires_in as i64, true)?;
+        set_cookie(&mut response, csrf_cookie_name(), &session.csrf_token, resp.expires_in as i64, false)?;
+        return Ok(response);
+    }
+    let mut fragment = format!(
+        "access_token={}&token_type={}&expires_in={}",
+        resp.access_token, resp.token_type, resp.expires_in
+    );
+    if !resp.refresh_token.is_empty() {
+        fragment.push_str(&format!("&refresh_token={}", resp.refresh_token));
+    }
+    let mut response = redirect(&format!("{}#{}", target, fragment))?;
+    set_cookie(&mut response, LOGIN_COOKIE, "", 0, true)?;
+    Ok(response)
+}
+
+/// Exchange the refresh token for new tokens, the issuer is chosen by its `iss` if it is a jwt.
+/// Return FORBIDDEN if the refresh token is rejected or the new access_token is invalid.
+pub async fn handle_refresh(form: HashMap<String, String>) -> Result<reply::Response, Rejection> {
+    let refresh_token = match form.get("refresh_token") {
+        Some(token) => token,
+        None => return Ok(bad_request("Missing refresh_token")),
+    };
+    if REVOKED_TOKENS.contains(refresh_token) {
+        info!("Reject refresh token revoked by logout");
+        return Err(reject::reject());
+    }
+    let iss = peek_claims(refresh_token).and_then(|claims| claims.iss);
+    let issuer = issuer::select(&ISSUERS, iss.as_deref());
+
+    let resp = request_token(
+        issuer,
+        &[("grant_type", "refresh_token"), ("refresh_token", refresh_token)],
+    )
+    .await?;
+    match parse_jwt_token(issuer, &resp.access_token) {
+        Ok(_) => Ok(token_json(&resp)),
+        Err(err) => {
+            info!("Reject refreshed token: {}", err);
+            Err(reject::reject())
+        }
+    }
+}
+
+/// End the session of the access token of the request and the `refresh_token` in the form.
+/// Both are revoked at the issuer if it has a revocation endpoint, and rejected by the gateway
+/// from now on until they expire. A refresh token which can be revoked neither way is refused.
+pub async fn handle_logout(
+    token: Option<String>,
+    session: Option<String>,
+    csrf: Option<String>,
+    form: HashMap<String, String>,
+) -> Result<reply::Response, Rejection> {
+    let (token, session) = match (token, session) {
+        (None, Some(id)) if sessions_enabled() => {
+            match session_token(&id, "POST", csrf.as_deref()) {
+                Ok(token) => {
+                    SESSIONS.remove(&id);
+                    (Some(token), true)
+                }
+                Err(response) => return Ok(*response),
+            }
+        }
+        (token, _) => (token, false),
+    };
+    let refresh_token = form.get("refresh_token");
+    if token.is_none() && refresh_token.is_none() {
+        return Ok(bad_request("Missing access token or refresh_token"));
+    }
+
+    let claims = token.as_deref().and_then(peek_claims);
+    let iss = match &claims {
+        Some(claims) => claims.iss.clone(),
+        None => refresh_token.and_then(|token| peek_claims(token)).and_then(|claims| claims.iss),
+    };
+    let issuer = issuer::select(&ISSUERS, iss.as_deref());
+    let revocation = issuer.endpoints().revocation;
+
+    // The gateway remembers refresh tokens as well so that /refresh refuses them.
+    // Only tokens signed by the issuer are remembered, which bounds the list by issued tokens.
+    let refresh_expiry = refresh_token.and_then(|token| refresh_token_expiry(issuer, token));
+    if refresh_token.is_some() && refresh_expiry.is_none() && revocation.is_none() {
+        return Ok(bad_request("Refresh token can not be revoked"));
+    }
+
+    // Only remember tokens which are still accepted, so that the list is bounded by valid tokens
+    let access_token = match &token {
+        Some(token) => match authenticate(token).await? {
+            Ok(_) => Some(token),
+            Err(reason) => {
+                debug!("Skip revoking access token: {}", reason);
+                None
+            }
+        },
+        None => None,
+    };
+    if let Some(token) = access_token {
+        let exp = claims.and_then(|claims| claims.exp).map(|exp| exp as i64);
+        // Tokens without exp are remembered for a day
+        REVOKED_TOKENS.revoke(token, exp.unwrap_or_else(|| Utc::now().timestamp() + 86400));
+        INTROSPECT_CACHE.remove(token);
+    }
+
+    if let (Some(token), Some(expires_at)) = (refresh_token, refresh_expiry) {
+        REVOKED_TOKENS.revoke(token, expires_at);
+    }
+
+    match revocation {
+        Some(url) => {
+            if let Some(token) = access_token {
+                revoke(issuer, &url, token, "access_token").await?;
+            }
+            if let Some(token) = refresh_token {
+                revoke(issuer, &url, token, "refresh_token").await?;
+            }
+        }
+        None => info!(
+            "Issuer {} has no revocation endpoint, tokens are only revoked by the gateway",
+            issuer.name()
+        ),
+    }
+
+    let mut response = reply::with_status(reply::reply(), StatusCode::OK).into_response();
+    if session {
+        set_cookie(&mut response, session_cookie_name(), "", 0, true)?;
+        set_cookie(&mut response, csrf_cookie_name(), "", 0, false)?;
+    }
+    Ok(response)
+}
+
+#[cfg(feature = "builtin-casbin")]
+fn key_store() -> &'static SqlxAdapter {
+    ADAPTER.get().expect("Permission adapter not loaded")
+}
+
+#[cfg(feature = "builtin-casbin")]
+fn key_store_error(err: casbin::Error) -> Rejection {
+    error!("{}", err);
+    reject::custom(CustomRejection { msg: "Access api keys in database failed".to_string() })
+}
+
+/// Look up the api key by the hash of its secret.
+/// Return the key, or the reason why it is not valid.
+#[cfg(feature = "builtin-casbin")]
+async fn authenticate_api_key(secret: &str) -> Result<Result<ApiKey, String>, Rejection> {
+    let key = match key_store().find_api_key(&api_keys::hash(secret)).await.map_err(key_store_error)? {
+        Some(key) => key,
+        None => return Ok(Err("Unknown api key".to_string())),
+    };
+    let now = Utc::now().timestamp();
+    if api_keys::expired(&key, now) {
+        return Ok(Err(format!("Api key {} has expired", key.id)));
+    }
+    // Record the usage at most once a minute, without delaying the request
+    if key.last_used_at.is_none_or(|last_used_at| now - last_used_at >= 60) {
+        let id = key.id;
+        tokio::spawn(async move {
+            if let Err(err) = key_store().touch_api_key(id, now).await {
+                warn!("Update last used time of api key {} failed: {}", id, err);
+            }
+        });
+    }
+    Ok(Ok(key))
+}
+
+/// Subject of the user owning the access token, api keys can not manage api keys.
+/// Return the reason if the token is not valid.
+#[cfg(feature = "builtin-casbin")]
+async fn api_key_owner(token: Option<String>) -> Result<Result<String, String>, Rejection> {
+    let token = match token {
+        Some(token) if !api_keys::is_api_key(&token) => token,
+        Some(_) => return Ok(Err("Api keys can not manage api keys".to_string())),
+        None => return Ok(Err("Missing access token".to_string())),
+    };
+    Ok(authenticate(&token).await?.map(|(issuer, user)| issuer.subject(&user)))
+}
+
+/// Issue an api key for the user of the access token.
+/// The form carries the `name`, and optionally `expires_in` seconds, `path` prefix and
+/// comma separated `methods` to restrict the key. The secret is only returned this time.
+#[cfg(feature = "builtin-casbin")]
+pub async fn handle_create_api_key(
+    token: Option<String>,
+    form: HashMap<String, String>,
+) -> Result<reply::Response, Rejection> {
+    let subject = match api_key_owner(token).await? {
+        Ok(subject) => subject,
+        Err(reason) => return Ok(unauthorized(&reason)),
+    };
+    let name = match form.get("name") {
+        Some(name) if !name.is_empty() => name.clone(),
+        _ => return Ok(bad_request("Missing name")),
+    };
+    let now = Utc::now().timestamp();
+    let expires_at = match form.get("expires_in").map(|secs| secs.parse::<u32>()) {
+        Some(Ok(secs)) => Some(now + secs as i64),
+        Some(Err(_)) => return Ok(bad_request("Invalid expires_in")),
+        None => None,
+    };
+
+    let secret = api_keys::generate();
+    let mut key = ApiKey {
+        id: 0,
+        subject,
+        name,
+        path_scope: form.get("path").filter(|path| !path.is_empty()).cloned(),
+        method_scope: form.get("methods").filter(|methods| !methods.is_empty()).cloned(),
+        expires_at,
+        last_used_at: None,
+        created_at: now,
+    };
+    key.id = key_store()
+        .add_api_key(&api_keys::hash(&secret), &key)
+        .await
+        .map_err(key_store_error)?;
+    info!("Issue api key {} for {}", key.id, key.subject);
+
+    let mut json = serde_json::to_value(&key).unwrap_or_default();
+    json["key"] = serde_json::Value::String(secret);
+    Ok(reply::json(&json).into_response())
+}
+
+/// List the api keys of the user of the access token, without their secrets.
+#[cfg(feature = "builtin-casbin")]
+pub async fn handle_list_api_keys(token: Option<String>) -> Result<reply::Response, Rejection> {
+    let subject = match api_key_owner(token).await? {
+        Ok(subject) => subject,
+        Err(reason) => return Ok(unauthorized(&reason)),
+    };
+    let keys = key_store().list_api_keys(&subject).await.map_err(key_store_error)?;
+    Ok(reply::json(&keys).into_response())
+}
 
-    if enforce(if cfg!(feature = "builtin-casbin") { &sub } else { &token }, path, method).await? {
-        let mut response = reply::with_status(reply::reply(), StatusCode::OK).into_response();
-        let remote_user = HeaderValue::from_str(&sub).map_err(|err| {
+/// Revoke an api key of the user of the access token.
+#[cfg(feature = "builtin-casbin")]
+pub async fn handle_delete_api_key(
+    id: i64,
+    token: Option<String>,
+) -> Result<reply::Response, Rejection> {
+    let subject = match api_key_owner(token).await? {
+        Ok(subject) => subject,
+        Err(reason) => return Ok(unauthorized(&reason)),
+    };
+    if key_store().remove_api_key(id, &subject).await.map_err(key_store_error)? {
+        info!("Revoke api key {} of {}", id, subject);
+        Ok(reply::with_status(reply::reply(), StatusCode::OK).into_response())
+    } else {
+        Ok(reply::with_status(reply::reply(), StatusCode::NOT_FOUND).into_response())
+    }
+}
+
+/// Location of the login entrypoint for an unauthenticated browser request,
+/// carrying the original url as the return target.
+/// None if `login_url` is not configured, or the request does not accept html or is not a GET.
+fn login_location(
+    accept: Option<&str>,
+    host: Option<&str>,
+    proto: Option<&str>,
+    method: &str,
+    uri: &str,
+) -> Option<String> {
+    let login_url = CONFIG.login_url.as_ref()?;
+    if !matches!(method, "GET" | "HEAD") || !accept?.contains("text/html") {
+        return None;
+    }
+    // Other hosts are only returned to if allowed, otherwise stay on the same host
+    let return_to = match host {
+        Some(host) if allowed_return_host(host.split(':').next().unwrap_or(host)) => {
+            format!("{}://{}{}", proto.unwrap_or("https"), host, uri)
+        }
+        _ => uri.to_string(),
+    };
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("return_to", &return_to)
+        .finish();
+    let separator = if login_url.contains('?') { '&' } else { '?' };
+    Some(format!("{}{}{}", login_url, separator, query))
+}
+
+/// Steps normalizing request paths, decoding, merging slashes and resolving dot segments
+/// by default. Ambiguous paths are rejected unless `path_strict` is false.
+fn path_options() -> normalize::Options {
+    let steps = CONFIG
+        .path_normalization
+        .as_deref()
+        .unwrap_or(&[PathStep::Decode, PathStep::MergeSlashes, PathStep::ResolveDots]);
+    normalize::Options::new(steps, CONFIG.path_strict.unwrap_or(true))
+}
+
+/// Object enforced by casbin: the path, followed by the query parameters selected for it
+/// by `query_params` in a canonical form, such as `/api/export?format=full`.
+/// Parameters are sorted by name then value, and access tokens are never included.
+fn authz_object(path: &str, query: Option<&str>) -> String {
+    let names = match CONFIG.query_params.iter().flatten().find(|conf| normalize::under(path, &conf.path)) {
+        Some(conf) => &conf.params,
+        None => return path.to_string(),
+    };
+    let mut params = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .filter(|(name, _)| name != "access_token" && names.iter().any(|selected| selected == name))
+        .collect::<Vec<_>>();
+    if params.is_empty() {
+        return path.to_string();
+    }
+    params.sort();
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .finish();
+    format!("{}?{}", path, query)
+}
+
+/// Every request to microservices behind will be handled in this function. 
+/// The function will do authentication first to confirm the access_token is valid. 
+/// Then it will do authorization using casbin to check the request permission. 
+/// (sub, obj, act) <-> (owner/name, request path, lowercase request method)
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_authenticate(
+    token: Option<String>,
+    session: Option<String>,
+    csrf: Option<String>,
+    accept: Option<String>,
+    host: Option<String>,
+    proto: Option<String>,
+    dpop: Option<String>,
+    basic: Option<(String, String)>,
+    cert: Option<String>,
+    method: String,
+    path: String,
+) -> Result<impl Reply, Rejection> {
+    if method == "OPTIONS" {
+        // CORS precheck request, answered whatever the path
+        return Ok(reply::with_status(reply::reply(), StatusCode::OK).into_response());
+    }
+
+    // Browsers are sent to log in instead of getting a bare status
+    let login = login_location(accept.as_deref(), host.as_deref(), proto.as_deref(), &method, &path);
+
+    // Remove url params, DPoP proofs are made for the path as it is sent
+    let raw_path = path.split(['?', '#']).next().unwrap_or_default().to_string();
+    let query = path.split('#').next().unwrap_or_default().split_once('?').map(|(_, query)| query.to_string());
+    // Policies are matched against the normalized path
+    let path = match normalize::path(&path, &path_options()) {
+        Ok(path) => path,
+        Err(reason) => {
+            info!("Reject path {}: {}", raw_path, reason);
+            return Ok(bad_request(&reason));
+        }
+    };
+
+    let object = authz_object(&path, query.as_deref());
+    let class = routes::classify(host.as_deref(), &path, &method);
+    // Only DPoP-bound access tokens are accepted on required paths,
+    // whatever the route class and other credentials
+    let bound_only = dpop_required(&path);
+    let unbound = || dpop_unauthorized(&format!("Path {} requires a DPoP-bound token", path));
+    let token = if class == RouteClass::Public && !bound_only {
+        return grant(class, anonymous_subject(), None, &object, &method).await;
+    } else {
+        // The Authorization header takes precedence over the session cookie,
+        // then the basic credentials and the client certificate
+        match (token, session, basic, cert) {
+            (Some(token), _, _, _) => token,
+            (None, Some(id), _, _) if sessions_enabled() => {
+                match session_token(&id, &method, csrf.as_deref()) {
+                    Ok(token) => token,
+                    Err(response) => match login {
+                        Some(location) if response.status() == StatusCode::UNAUTHORIZED => {
+                            return redirect(&location)
+                        }
+                        _ => return Ok(*response),
+                    },
+                }
+            }
+            // Enforced by the builtin casbin only, casdoor api needs an access token
+            (None, _, Some(_), _) if bound_only => return Ok(unbound()),
+            (None, _, Some((username, password)), _) => {
+                let (user, token) = match authenticate_basic(&username, &password).await? {
+                    Ok(res) => res,
+                    Err(reason) => return Ok(basic_challenge(&reason)),
+                };
+                let issuer = &ISSUERS[0];
+                return authorize(class, issuer, &issuer.subject(&user), Some(&user), &token, object, method).await;
+            }
+            #[cfg(feature = "builtin-casbin")]
+            (None, _, None, Some(_)) if bound_only => return Ok(unbound()),
+            #[cfg(feature = "builtin-casbin")]
+            (None, _, None, Some(cert)) => {
+                let sub = match mtls::identity(&cert, CONFIG.mtls_identity.unwrap_or_default()) {
+                    Ok(name) => format!("{}{}", CONFIG.mtls_subject_prefix.as_deref().unwrap_or("cert/"), name),
+                    Err(reason) => return Ok(unauthorized(&reason)),
+                };
+                return authorize(class, &ISSUERS[0], &sub, None, "", object, method).await;
+            }
+            _ => {
+                // Public access granted by policies of the anonymous subject
+                #[cfg(feature = "builtin-casbin")]
+                if CONFIG.anonymous_access.unwrap_or(false)
+                    && class == RouteClass::Authorized
+                    && !bound_only
+                    && enforce(&ISSUERS[0], anonymous_subject(), object.clone(), method.clone()).await?
+                {
+                    return grant(class, anonymous_subject(), None, &object, &method).await;
+                }
+                match login {
+                    Some(location) => return redirect(&location),
+                    // Legacy clients only send credentials once challenged
+                    None if basic_auth_enabled() => return Ok(basic_challenge("Missing credentials")),
+                    None => return Err(reject::reject()),
+                }
+            }
+        }
+    };
+
+    // Credentials are never logged, api keys are only named by their id after lookup
+    let msg = format!("{{method: {}, path: {}}}", method, path);
+    debug!("Authenticate inbound request: {}", msg);
+
+    // Authentication
+
+    #[cfg(feature = "builtin-casbin")]
+    if api_keys::is_api_key(&token) {
+        if bound_only {
+            return Ok(unbound());
+        }
+        let key = match authenticate_api_key(&token).await? {
+            Ok(key) => key,
+            Err(reason) => return Ok(unauthorized(&reason)),
+        };
+        debug!("Authenticate with api key {} of {}", key.id, key.subject);
+        if !api_keys::in_scope(&key, &method, &path) {
+            info!("Api key {} of {} is out of scope for {} {}", key.id, key.subject, method, path);
+            return Ok(reply::with_status(reply::reply(), StatusCode::FORBIDDEN).into_response());
+        }
+        return authorize(class, &ISSUERS[0], &key.subject, None, &token, object, method).await;
+    }
+
+    let (issuer, user) = match authenticate(&token).await? {
+        Ok(res) => res,
+        Err(reason) => match login {
+            Some(location) => {
+                info!("Reject token: {}", reason);
+                return redirect(&location);
+            }
+            None => return Ok(unauthorized(&reason)),
+        },
+    };
+    let req = dpop::Request {
+        method: &method,
+        proto: proto.as_deref(),
+        host: host.as_deref(),
+        path: &raw_path,
+    };
+    if let Err(reason) = check_dpop(&token, dpop.as_deref(), &req, bound_only) {
+        return Ok(dpop_unauthorized(&reason));
+    }
+
+    // Authorization
+
+    let sub = issuer.subject(&user);
+    authorize(class, issuer, &sub, Some(&user), &token, object, method).await
+}
+
+/// Enforce the permission of the subject, passing the subject in `Remote-User` header if allowed.
+async fn authorize(
+    class: RouteClass,
+    issuer: &Issuer,
+    sub: &str,
+    user: Option<&CasdoorUser>,
+    token: &str,
+    object: String,
+    method: String,
+) -> Result<reply::Response, Rejection> {
+    // Authenticated routes are open to every authenticated subject
+    if class == RouteClass::Authorized
+        && !enforce(issuer, if cfg!(feature = "builtin-casbin") { sub } else { token }, object.clone(), method.clone()).await?
+    {
+        return Ok(reply::with_status(reply::reply(), StatusCode::FORBIDDEN).into_response());
+    }
+    grant(class, sub, user, &object, &method).await
+}
+
+/// Subject of requests to public routes and requests without credentials.
+fn anonymous_subject() -> &'static str {
+    CONFIG.anonymous_subject.as_deref().unwrap_or("anonymous")
+}
+
+/// Let the request pass, telling upstreams who the subject is.
+async fn grant(
+    class: RouteClass,
+    sub: &str,
+    user: Option<&CasdoorUser>,
+    path: &str,
+    method: &str,
+) -> Result<reply::Response, Rejection> {
+    let mut response = reply::with_status(reply::reply(), StatusCode::OK).into_response();
+    let remote_user = HeaderValue::from_str(sub).map_err(|err| {
+        error!("{}", err);
+        reject::custom(CustomRejection { msg: "Add Remote-User header failed".to_string() })
+    })?;
+    response.headers_mut().append("Remote-User", remote_user);
+    if let Some(headers) = &CONFIG.identity_headers {
+        let roles = match headers.contains_key("roles") {
+            true => implicit_roles(sub).await?,
+            false => Vec::new(),
+        };
+        let user = user.map(|user| serde_json::to_value(user).unwrap_or_default());
+        for (field, name) in headers {
+            let value = identity_values(user.as_ref(), &roles, field)
+                .iter()
+                .map(|value| utf8_percent_encode(value, IDENTITY_VALUE).to_string())
+                .collect::<Vec<String>>()
+                .join(",");
+            // Always set so that a header sent by the client never reaches upstreams,
+            // names are checked when loading the configuration
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(&value)) {
+                response.headers_mut().insert(name, value);
+            }
+        }
+    }
+    if let Some(signer) = SIGNER.as_ref() {
+        let token = internal_token(signer, class, sub, user, path, method).await?;
+        let value = HeaderValue::from_str(&token).map_err(|err| {
             error!("{}", err);
-            reject::custom(CustomRejection { msg: "Add Remote-User header failed".to_string() })
+            reject::custom(CustomRejection { msg: "Add internal token header failed".to_string() })
         })?;
-        response.headers_mut().append("Remote-User", remote_user);
-        Ok(response)
-    } else {
-        Ok(reply::with_status(reply::reply(), StatusCode::FORBIDDEN).into_response())
+        response.headers_mut().insert(internal_token_header(), value);
+    }
+    Ok(response)
+}
+
+/// Characters encoded in identity headers, besides non-ASCII ones.
+/// Commas are encoded as they separate the values of a list.
+const IDENTITY_VALUE: &AsciiSet = &CONTROLS.add(b'%').add(b',');
+
+/// Values of an identity field of the user, empty if the user has no such field.
+/// Fields are named in snake case like `display_name`, `properties.<key>` names a
+/// custom property and `roles` the implicit roles of the subject.
+fn identity_values(user: Option<&Value>, roles: &[String], field: &str) -> Vec<String> {
+    if field == "roles" {
+        return roles.to_vec();
+    }
+    let value = match field.strip_prefix("properties.") {
+        Some(key) => user.and_then(|user| user.get("properties")?.get(key)),
+        None => {
+            let name = camel_case(field);
+            if credential_field(&name) {
+                None
+            } else {
+                user.and_then(|user| user.get(&name))
+            }
+        }
+    };
+    match value {
+        Some(Value::String(value)) => vec![value.clone()],
+        Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+        Some(value @ (Value::Bool(_) | Value::Number(_))) => vec![value.to_string()],
+        _ => Vec::new(),
+    }
+}
+
+/// Whether the casdoor user field holds credentials,
+/// which are never handed out to upstreams whatever is configured.
+fn credential_field(name: &str) -> bool {
+    matches!(name, "password" | "hash" | "preHash")
+}
+
+/// Convert a snake case field name to the camel case used by casdoor.
+fn camel_case(field: &str) -> String {
+    let mut parts = field.split('_');
+    let mut name = parts.next().unwrap_or_default().to_string();
+    for part in parts {
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            name.push(first.to_ascii_uppercase());
+            name.push_str(chars.as_str());
+        }
+    }
+    name
+}
+
+fn internal_token_header() -> &'static str {
+    CONFIG.internal_jwt_header.as_deref().unwrap_or("X-Internal-Token")
+}
+
+/// Sign the internal token telling upstreams who is authorized for the request.
+async fn internal_token(
+    signer: &Signer,
+    class: RouteClass,
+    sub: &str,
+    user: Option<&CasdoorUser>,
+    path: &str,
+    method: &str,
+) -> Result<String, Rejection> {
+    let now = Utc::now().timestamp();
+    let mut claims = json!({
+        "iss": CONFIG.internal_jwt_issuer.as_deref().unwrap_or(env!("CARGO_PKG_NAME")),
+        "sub": sub,
+        "iat": now,
+        "exp": now + CONFIG.internal_jwt_lifetime.unwrap_or(60) as i64,
+        "roles": implicit_roles(sub).await?,
+        "authz": { "decision": "allow", "class": class, "method": method, "path": path },
+    });
+    if let Some(aud) = &CONFIG.internal_jwt_audience {
+        claims["aud"] = json!(aud);
+    }
+    if let Some(user) = user {
+        let user = serde_json::to_value(user).unwrap_or_default();
+        let default_fields = ["displayName".to_string(), "email".to_string()];
+        let fields = CONFIG.internal_jwt_user_fields.as_deref().unwrap_or(&default_fields);
+        claims["user"] = fields
+            .iter()
+            .filter(|field| !credential_field(field))
+            .filter_map(|field| Some((field.clone(), user.get(field)?.clone())))
+            .collect::<serde_json::Map<String, Value>>()
+            .into();
+    }
+
+    signer.sign(&claims).map_err(|err| {
+        error!("{}", err);
+        reject::custom(CustomRejection { msg: "Sign internal token failed".to_string() })
+    })
+}
+
+/// Roles the subject has directly or through other roles.
+#[cfg(feature = "builtin-casbin")]
+async fn implicit_roles(sub: &str) -> Result<Vec<String>, Rejection> {
+    let enforcer = ENFORCER
+        .get()
+        .ok_or(reject::custom(CustomRejection {
+            msg: "Get enforcer from memory failed (None Enforcer)".to_string(),
+        }))?
+        .read()
+        .await;
+    let role_manager = enforcer.get_role_manager();
+    let mut roles: Vec<String> = Vec::new();
+    let mut queue = vec![sub.to_string()];
+    while let Some(name) = queue.pop() {
+        for role in role_manager.write().get_roles(&name, None) {
+            if !roles.contains(&role) {
+                queue.push(role.clone());
+                roles.push(role);
+            }
+        }
+    }
+    Ok(roles)
+}
+
+/// Roles are unknown to the gateway when casdoor api enforces permission.
+#[cfg(not(feature = "builtin-casbin"))]
+async fn implicit_roles(_sub: &str) -> Result<Vec<String>, Rejection> {
+    Ok(Vec::new())
+}
+
+/// Public key of the internal tokens as a JWK Set.
+pub async fn handle_jwks() -> Result<impl Reply, Rejection> {
+    match SIGNER.as_ref() {
+        Some(signer) => Ok(reply::json(&signer.jwks())),
+        None => Err(reject::not_found()),
     }
 }
 
@@ -222,3 +1487,85 @@ pub async fn err_handle(err: Rejection) -> Result<impl Reply, Infallible> {
         Ok(reply::with_status(reply::html("".to_string()), StatusCode::FORBIDDEN))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_fields() {
+        let user = json!({
+            "displayName": "Alice",
+            "email": "alice@example.com",
+            "groups": ["staff", "ops"],
+            "isAdmin": false,
+            "password": "secret",
+            "hash": "hashed",
+            "preHash": "prehashed",
+            "properties": { "team": "billing" },
+        });
+        let roles = ["admin".to_string()];
+        let values = |field: &str| identity_values(Some(&user), &roles, field);
+
+        assert_eq!(values("display_name"), ["Alice"]);
+        assert_eq!(values("displayName"), ["Alice"]);
+        assert_eq!(values("groups"), ["staff", "ops"]);
+        assert_eq!(values("is_admin"), ["false"]);
+        assert_eq!(values("properties.team"), ["billing"]);
+        assert_eq!(values("roles"), ["admin"]);
+        assert!(values("phone").is_empty());
+        assert!(identity_values(None, &[], "email").is_empty());
+
+        for credential in ["password", "hash", "pre_hash", "preHash", "pre_Hash"] {
+            assert!(values(credential).is_empty(), "{}", credential);
+        }
+    }
+
+    #[test]
+    fn local_return_targets() {
+        let target = |target: &str| checked_return_target(target, &[]);
+        assert_eq!(target("/dashboard").as_deref(), Some("/dashboard"));
+        assert_eq!(target("/a/b?x=1#top").as_deref(), Some("/a/b?x=1#top"));
+        assert_eq!(target("/%2F/evil.com").as_deref(), Some("/%2F/evil.com"));
+
+        // Taken by browsers as urls of other hosts
+        for evil in [
+            "//evil.com",
+            "/\\evil.com",
+            "/\\/evil.com",
+            "/\t/evil.com",
+            "/\n/evil.com",
+            "/\r\n/evil.com",
+            "\t//evil.com",
+            " //evil.com",
+            "/\u{0}/evil.com",
+            "evil.com",
+            "javascript:alert(1)",
+        ] {
+            assert_eq!(target(evil), None, "{:?}", evil);
+        }
+    }
+
+    #[test]
+    fn external_return_targets() {
+        let allowed = ["app.example.com".to_string()];
+        let target = |target: &str| checked_return_target(target, &allowed);
+        assert_eq!(
+            target("https://app.example.com/home").as_deref(),
+            Some("https://app.example.com/home")
+        );
+        assert_eq!(target("HTTP://APP.example.com").as_deref(), Some("http://app.example.com/"));
+
+        for denied in [
+            "https://evil.com/",
+            "https://app.example.com.evil.com/",
+            "https://app.example.com@evil.com/",
+            "https://app.example.com\t/",
+            "ftp://app.example.com/",
+            "javascript://app.example.com/%0Aalert(1)",
+        ] {
+            assert_eq!(target(denied), None, "{:?}", denied);
+        }
+        assert_eq!(checked_return_target("https://app.example.com/", &[]), None);
+    }
+}
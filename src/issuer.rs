@@ -0,0 +1,167 @@
+use std::sync::RwLock;
+
+use jsonwebtoken::{Algorithm, Validation};
+use serde_json::Value;
+
+use crate::entity::{CasdoorUser, IssuerConfig, ProviderKind};
+use crate::keys::{self, KeyStore};
+use crate::provider::{Casdoor, Endpoints, Oidc, Provider};
+
+/// An identity provider whose tokens are accepted, with its own keys and validation rules.
+pub struct Issuer {
+    pub conf: IssuerConfig,
+    pub provider: Box<dyn Provider>,
+    pub keys: KeyStore,
+    pub validation: Validation,
+    /// Audiences required for user tokens, None if not checked
+    audiences: Option<Vec<String>>,
+    endpoints: RwLock<Endpoints>,
+}
+
+impl Issuer {
+    /// Build an issuer, its static key is loaded from `jwt_pub_key` immediately.
+    /// `issuers` restricts the accepted `iss` claims, None accepts any issuer.
+    pub fn new(
+        conf: IssuerConfig,
+        issuers: Option<&[String]>,
+        algorithms: &[Algorithm],
+        leeway: u64,
+        cert_expiry_warning_days: i64,
+    ) -> Self {
+        let static_key = conf.jwt_pub_key.as_ref().map(|pem| {
+            keys::load_pem_key(pem, cert_expiry_warning_days).unwrap_or_else(|err| {
+                panic!("Load jwt_pub_key of issuer {} failed: {}", name(&conf), err)
+            })
+        });
+
+        let mut validation = Validation::new(algorithms[0]);
+        validation.algorithms = algorithms.to_vec();
+        validation.validate_nbf = true;
+        validation.leeway = leeway;
+        // Tokens are issued to this application by default, an empty list disables the check
+        let audiences = match &conf.jwt_audiences {
+            Some(aud) if aud.is_empty() => None,
+            Some(aud) => Some(aud.clone()),
+            None => Some(vec![conf.client_id.clone()]),
+        };
+        match &audiences {
+            // Client credentials tokens are issued to the calling services themselves
+            Some(aud) => validation.set_audience(
+                &[aud.as_slice(), conf.service_clients.as_deref().unwrap_or_default()].concat(),
+            ),
+            None => validation.aud = None,
+        }
+        if let Some(iss) = issuers {
+            validation.set_issuer(iss);
+        }
+
+        let provider: Box<dyn Provider> = match conf.provider.unwrap_or_default() {
+            ProviderKind::Casdoor => Box::new(Casdoor),
+            ProviderKind::Oidc => Box::new(Oidc {
+                org_name: conf.org_name.clone(),
+                username_claim: conf
+                    .username_claim
+                    .clone()
+                    .unwrap_or_else(|| "preferred_username".to_string()),
+            }),
+        };
+        let endpoints = RwLock::new(provider.default_endpoints(&conf.endpoint));
+
+        Self {
+            conf,
+            provider,
+            keys: KeyStore::new(static_key),
+            validation,
+            audiences,
+            endpoints,
+        }
+    }
+
+    /// Current endpoints of the provider.
+    pub fn endpoints(&self) -> Endpoints {
+        self.endpoints.read().unwrap().clone()
+    }
+
+    /// Replace the endpoints by the discovered ones.
+    /// Endpoints missing from the discovery document are kept.
+    pub fn set_endpoints(&self, discovered: Endpoints) {
+        let mut endpoints = self.endpoints.write().unwrap();
+        let keep = |new: Option<String>, old: &Option<String>| new.or_else(|| old.clone());
+        *endpoints = Endpoints {
+            authorization: keep(discovered.authorization, &endpoints.authorization),
+            token: keep(discovered.token, &endpoints.token),
+            introspection: keep(discovered.introspection, &endpoints.introspection),
+            revocation: keep(discovered.revocation, &endpoints.revocation),
+            userinfo: keep(discovered.userinfo, &endpoints.userinfo),
+            jwks: keep(discovered.jwks, &endpoints.jwks),
+        };
+    }
+
+    /// Jwks url of the issuer. The configured one takes precedence,
+    /// the provider's one is only used if `jwt_pub_key` is not configured either.
+    pub fn jwks_url(&self) -> Option<String> {
+        match (&self.conf.jwks_url, &self.conf.jwt_pub_key) {
+            (Some(url), _) => Some(url.clone()),
+            (None, Some(_)) => None,
+            (None, None) => self.endpoints().jwks,
+        }
+    }
+
+    /// Identity of a verified token. Client credentials tokens of `service_clients`
+    /// are named by their client id in the "app" organization, other clients are rejected.
+    pub fn identity(&self, claims: Value) -> Result<CasdoorUser, String> {
+        if let Some(client) = self.provider.client(&claims) {
+            if !self.conf.service_clients.iter().flatten().any(|allowed| *allowed == client) {
+                return Err(format!("Client {} is not an allowed service client", client));
+            }
+            return Ok(CasdoorUser {
+                owner: "app".to_string(),
+                name: client,
+                r#type: "application".to_string(),
+                ..Default::default()
+            });
+        }
+
+        // Audiences of service clients are only accepted for their client credentials tokens
+        if let Some(audiences) = &self.audiences {
+            let aud = match claims.get("aud") {
+                Some(Value::Array(aud)) => aud.iter().filter_map(Value::as_str).collect(),
+                Some(Value::String(aud)) => vec![aud.as_str()],
+                _ => vec![],
+            };
+            if !aud.iter().any(|aud| audiences.iter().any(|allowed| allowed == aud)) {
+                return Err(format!("Token audience {:?} is not accepted for users", aud));
+            }
+        }
+        self.provider.identity(claims)
+    }
+
+    pub fn name(&self) -> &str {
+        name(&self.conf)
+    }
+
+    /// Casbin subject of the user, prefixed to keep users of different issuers apart.
+    pub fn subject(&self, user: &CasdoorUser) -> String {
+        format!(
+            "{}{}/{}",
+            self.conf.subject_prefix.as_deref().unwrap_or(""),
+            user.owner,
+            user.name
+        )
+    }
+}
+
+fn name(conf: &IssuerConfig) -> &str {
+    conf.iss.as_deref().unwrap_or("default")
+}
+
+/// Choose the issuer of a token by its `iss` claim.
+/// The first issuer is the default one, used when no other issuer matches.
+pub fn select<'a>(issuers: &'a [Issuer], iss: Option<&str>) -> &'a Issuer {
+    iss.and_then(|iss| {
+        issuers[1..]
+            .iter()
+            .find(|issuer| issuer.conf.iss.as_deref() == Some(iss))
+    })
+    .unwrap_or(&issuers[0])
+}
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::RwLock;
+
+use chrono::Utc;
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, JwkSet};
+use jsonwebtoken::DecodingKey;
+use log::{info, warn};
+use x509_parser::parse_x509_certificate;
+use x509_parser::prelude::{FromDer, SubjectPublicKeyInfo};
+use x509_parser::public_key::PublicKey;
+
+#[derive(Default)]
+struct JwksKeys {
+    current: HashMap<String, DecodingKey>,
+    previous: HashMap<String, DecodingKey>,
+}
+
+/// Keys used to verify the signature of access tokens.
+/// Keys fetched from the jwks endpoint are matched by `kid`. Keys which disappear from the
+/// endpoint are still accepted until the next refresh, so that tokens signed before a
+/// rotation keep working during the rotation window.
+pub struct KeyStore {
+    static_key: Option<DecodingKey>,
+    jwks: RwLock<JwksKeys>,
+}
+
+impl KeyStore {
+    /// Create a key store with the key configured by `jwt_pub_key`, if any.
+    pub fn new(static_key: Option<DecodingKey>) -> Self {
+        Self {
+            static_key,
+            jwks: RwLock::new(JwksKeys::default()),
+        }
+    }
+
+    /// Get the keys which may have signed a token with the given `kid`.
+    /// Tokens without `kid` are checked against every known key.
+    pub fn candidates(&self, kid: Option<&str>) -> Vec<DecodingKey> {
+        let jwks = self.jwks.read().unwrap();
+        let mut keys = match kid {
+            Some(kid) => jwks
+                .current
+                .get(kid)
+                .or_else(|| jwks.previous.get(kid))
+                .into_iter()
+                .cloned()
+                .collect(),
+            None => jwks
+                .current
+                .values()
+                .chain(jwks.previous.values())
+                .cloned()
+                .collect::<Vec<DecodingKey>>(),
+        };
+        keys.extend(self.static_key.iter().cloned());
+        keys
+    }
+
+    /// Replace the jwks keys by a freshly fetched set, return the number of usable keys.
+    pub fn update(&self, set: &JwkSet) -> usize {
+        let mut current = HashMap::new();
+        for jwk in &set.keys {
+            let kid = match &jwk.common.key_id {
+                Some(kid) => kid,
+                None => {
+                    warn!("Skip jwk without kid");
+                    continue;
+                }
+            };
+            let key = match &jwk.algorithm {
+                AlgorithmParameters::RSA(rsa) => DecodingKey::from_rsa_components(&rsa.n, &rsa.e),
+                AlgorithmParameters::EllipticCurve(ec) if ec.curve == EllipticCurve::P256 => ec_key(&ec.x, &ec.y),
+                _ => {
+                    warn!("Skip jwk {} with unsupported key type", kid);
+                    continue;
+                }
+            };
+            match key {
+                Ok(key) => {
+                    current.insert(kid.clone(), key);
+                }
+                Err(err) => warn!("Skip invalid jwk {}: {}", kid, err),
+            }
+        }
+
+        let count = current.len();
+        let mut jwks = self.jwks.write().unwrap();
+        let mut previous = std::mem::replace(&mut jwks.current, current);
+        previous.retain(|kid, _| !jwks.current.contains_key(kid));
+        jwks.previous = previous;
+        count
+    }
+}
+
+/// Build a P-256 key from the JWK coordinates, ring takes the uncompressed point.
+fn ec_key(x: &str, y: &str) -> Result<DecodingKey, jsonwebtoken::errors::Error> {
+    let coordinate = |value: &str| base64::decode_config(value, base64::URL_SAFE_NO_PAD);
+    let point = [vec![4], coordinate(x)?, coordinate(y)?].concat();
+    Ok(DecodingKey::from_ec_der(&point))
+}
+
+/// Load a token verification key from a PEM encoded X.509 certificate or public key.
+/// RSA and EC keys are supported. The validity window of a certificate is reported,
+/// certificates which are expired or not yet valid are refused,
+/// and a warning is logged if it expires within `warning_days`.
+pub fn load_pem_key(pem: &str, warning_days: i64) -> Result<DecodingKey, Box<dyn Error>> {
+    // The whole label is needed to tell the kinds of public keys apart
+    let pem = pem::parse(pem.trim())?;
+    match pem.tag.as_str() {
+        "CERTIFICATE" => {
+            let (_, cert) = parse_x509_certificate(&pem.contents)?;
+            let validity = cert.validity();
+            info!(
+                "Loaded certificate {}, valid from {} to {}",
+                cert.subject(),
+                validity.not_before,
+                validity.not_after
+            );
+
+            let now = Utc::now().timestamp();
+            if validity.not_before.timestamp() > now {
+                return Err(format!("Certificate is not valid before {}", validity.not_before).into());
+            }
+            let remaining_days = (validity.not_after.timestamp() - now) / 86400;
+            if remaining_days < 0 {
+                return Err(format!("Certificate expired at {}", validity.not_after).into());
+            }
+            if remaining_days < warning_days {
+                warn!(
+                    "Certificate expires in {} days at {}",
+                    remaining_days, validity.not_after
+                );
+            }
+
+            spki_key(cert.public_key())
+        }
+        "PUBLIC KEY" => {
+            let (_, spki) = SubjectPublicKeyInfo::from_der(&pem.contents)?;
+            spki_key(&spki)
+        }
+        "RSA PUBLIC KEY" => Ok(DecodingKey::from_rsa_der(&pem.contents)),
+        label => Err(format!("Unsupported PEM label \"{}\"", label).into()),
+    }
+}
+
+/// Build a verification key from the SubjectPublicKeyInfo.
+fn spki_key(spki: &SubjectPublicKeyInfo) -> Result<DecodingKey, Box<dyn Error>> {
+    // The key bit string is a PKCS#1 RSAPublicKey for RSA keys
+    // and an uncompressed point for EC keys, which is what ring expects
+    let key = &spki.subject_public_key.data;
+    match spki.parsed()? {
+        PublicKey::RSA(_) => Ok(DecodingKey::from_rsa_der(key)),
+        PublicKey::EC(_) => Ok(DecodingKey::from_ec_der(key)),
+        _ => Err(format!("Unsupported public key algorithm {}", spki.algorithm.algorithm).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::Validation;
+    use serde_json::json;
+
+    use crate::signer::Signer;
+
+    fn signer(pem: &str) -> Signer {
+        Signer::load(pem).unwrap()
+    }
+
+    fn jwks(signers: &[&Signer]) -> JwkSet {
+        let keys = signers.iter().flat_map(|signer| signer.jwks()["keys"].as_array().unwrap().clone());
+        serde_json::from_value(json!({ "keys": keys.collect::<Vec<_>>() })).unwrap()
+    }
+
+    /// Whether one of the candidates verifies a token signed by the signer.
+    fn verifies(keys: &[DecodingKey], signer: &Signer) -> bool {
+        let token = signer.sign(&json!({ "sub": "org/alice" })).unwrap();
+        let header = jsonwebtoken::decode_header(&token).unwrap();
+        let mut validation = Validation::new(header.alg);
+        validation.required_spec_claims.clear();
+        validation.validate_exp = false;
+        keys.iter()
+            .any(|key| jsonwebtoken::decode::<serde_json::Value>(&token, key, &validation).is_ok())
+    }
+
+    #[test]
+    fn rotation_keeps_previous_keys() {
+        let rsa = signer(include_str!("../testdata/rsa.key"));
+        let ec = signer(include_str!("../testdata/ec.key"));
+        let store = KeyStore::new(None);
+
+        assert_eq!(store.update(&jwks(&[&rsa])), 1);
+        assert!(verifies(&store.candidates(Some(rsa.kid())), &rsa));
+        assert!(store.candidates(Some(ec.kid())).is_empty());
+
+        // The EC key replaces the RSA key, tokens signed by the RSA key are still accepted
+        assert_eq!(store.update(&jwks(&[&ec])), 1);
+        assert!(verifies(&store.candidates(Some(ec.kid())), &ec));
+        assert!(verifies(&store.candidates(Some(rsa.kid())), &rsa));
+        assert_eq!(store.candidates(None).len(), 2);
+        // A kid only selects its own key
+        assert!(!verifies(&store.candidates(Some(ec.kid())), &rsa));
+
+        // Until the next refresh
+        assert_eq!(store.update(&jwks(&[&ec])), 1);
+        assert!(store.candidates(Some(rsa.kid())).is_empty());
+        assert_eq!(store.candidates(None).len(), 1);
+    }
+
+    #[test]
+    fn unusable_jwks_are_skipped() {
+        let ec = signer(include_str!("../testdata/ec.key"));
+        let mut set = jwks(&[&ec]);
+        set.keys[0].common.key_id = None;
+        let store = KeyStore::new(None);
+        assert_eq!(store.update(&set), 0);
+        assert!(store.candidates(None).is_empty());
+
+        let set: JwkSet = serde_json::from_value(json!({ "keys": [
+            { "kty": "oct", "kid": "secret", "k": "c2VjcmV0" },
+            { "kty": "EC", "kid": "p384", "crv": "P-384", "x": "AA", "y": "AA" },
+        ]}))
+        .unwrap();
+        assert_eq!(store.update(&set), 0);
+    }
+
+    #[test]
+    fn pem_keys() {
+        let rsa = signer(include_str!("../testdata/rsa.key"));
+        let ec = signer(include_str!("../testdata/ec.key"));
+        let load = |pem: &str| load_pem_key(pem, 30).unwrap();
+
+        for (pem, signer) in [
+            (include_str!("../testdata/rsa.crt"), &rsa),
+            (include_str!("../testdata/rsa.pub"), &rsa),
+            (include_str!("../testdata/rsa-pkcs1.pub"), &rsa),
+            (include_str!("../testdata/ec.crt"), &ec),
+            (include_str!("../testdata/ec.pub"), &ec),
+        ] {
+            assert!(verifies(&[load(pem)], signer), "{}", pem);
+        }
+        assert!(!verifies(&[load(include_str!("../testdata/ec.pub"))], &rsa));
+        // Surrounding whitespace of a multiline config value is ignored
+        assert!(verifies(&[load(&format!("\n  {}\n", include_str!("../testdata/ec.pub")))], &ec));
+    }
+
+    #[test]
+    fn refused_pem_keys() {
+        let err = |pem: &str| load_pem_key(pem, 30).err().unwrap().to_string();
+        assert!(err(include_str!("../testdata/expired.crt")).starts_with("Certificate expired at"));
+        assert!(err(include_str!("../testdata/future.crt")).starts_with("Certificate is not valid before"));
+        assert!(err(include_str!("../testdata/ec.key")).contains("Unsupported PEM label \"PRIVATE KEY\""));
+        assert!(load_pem_key("not a pem", 30).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::Utc;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// Seconds a user has to come back from the login page.
+pub const LOGIN_TTL: u64 = 600;
+
+/// A login started by `/login` and waiting for `/callback`.
+pub struct PendingLogin {
+    pub code_verifier: String,
+    pub return_to: String,
+    expires_at: i64,
+}
+
+/// Pending browser logins keyed by their `state`.
+/// A state can only be taken once, and expires if the user does not come back in time.
+pub struct LoginStore {
+    pending: Mutex<HashMap<String, PendingLogin>>,
+    ttl: i64,
+    capacity: usize,
+}
+
+impl LoginStore {
+    pub fn new(ttl: u64, capacity: usize) -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+            ttl: ttl as i64,
+            capacity,
+        }
+    }
+
+    /// Start a login returning to `return_to`, return its state and PKCE code verifier.
+    /// When full, the login which expires first is evicted, so that abandoned logins
+    /// cannot keep others from starting.
+    pub fn start(&self, return_to: String) -> (String, String) {
+        let now = Utc::now().timestamp();
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= self.capacity {
+            pending.retain(|_, login| login.expires_at > now);
+        }
+        if pending.len() >= self.capacity {
+            if let Some(state) = pending
+                .iter()
+                .min_by_key(|(_, login)| login.expires_at)
+                .map(|(state, _)| state.clone())
+            {
+                pending.remove(&state);
+            }
+        }
+
+        let state = random_string();
+        let code_verifier = random_string();
+        pending.insert(
+            state.clone(),
+            PendingLogin {
+                code_verifier: code_verifier.clone(),
+                return_to,
+                expires_at: now + self.ttl,
+            },
+        );
+        (state, code_verifier)
+    }
+
+    /// Take the pending login of the state if it has not expired.
+    pub fn take(&self, state: &str) -> Option<PendingLogin> {
+        let login = self.pending.lock().unwrap().remove(state)?;
+        if login.expires_at > Utc::now().timestamp() {
+            Some(login)
+        } else {
+            None
+        }
+    }
+}
+
+/// A browser session created by `/callback` in cookie mode.
+#[derive(Clone)]
+pub struct Session {
+    pub access_token: String,
+    pub csrf_token: String,
+    expires_at: i64,
+}
+
+/// Browser sessions keyed by the opaque id kept in the session cookie,
+/// so that tokens never reach the browser.
+pub struct SessionStore {
+    sessions: Mutex<HashMap<String, Session>>,
+    capacity: usize,
+}
+
+impl SessionStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            capacity,
+        }
+    }
+
+    /// Create a session of the access token lasting `ttl` seconds, return its id.
+    /// When full, the session which expires first is evicted.
+    pub fn create(&self, access_token: String, ttl: i64) -> (String, Session) {
+        let now = Utc::now().timestamp();
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= self.capacity {
+            sessions.retain(|_, session| session.expires_at > now);
+        }
+        if sessions.len() >= self.capacity {
+            if let Some(id) = sessions
+                .iter()
+                .min_by_key(|(_, session)| session.expires_at)
+                .map(|(id, _)| id.clone())
+            {
+                sessions.remove(&id);
+            }
+        }
+
+        let id = random_string();
+        let session = Session {
+            access_token,
+            csrf_token: random_string(),
+            expires_at: now + ttl,
+        };
+        sessions.insert(id.clone(), session.clone());
+        (id, session)
+    }
+
+    /// Get the session of the id if it has not expired.
+    pub fn get(&self, id: &str) -> Option<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(id) {
+            Some(session) if session.expires_at > Utc::now().timestamp() => Some(session.clone()),
+            Some(_) => {
+                sessions.remove(id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn remove(&self, id: &str) -> Option<Session> {
+        self.sessions.lock().unwrap().remove(id)
+    }
+}
+
+/// Compare the CSRF token sent by the client in constant time.
+pub fn verify_csrf(session: &Session, sent: &str) -> bool {
+    constant_time_eq(&session.csrf_token, sent)
+}
+
+/// Compare secrets without leaking the position of the first difference.
+pub fn constant_time_eq(expected: &str, sent: &str) -> bool {
+    let expected = expected.as_bytes();
+    let sent = sent.as_bytes();
+    expected.len() == sent.len()
+        && expected
+            .iter()
+            .zip(sent)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// 256 bits of randomness encoded in base64url.
+pub fn random_string() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// PKCE `S256` code challenge of the code verifier.
+pub fn code_challenge(code_verifier: &str) -> String {
+    base64::encode_config(
+        Sha256::digest(code_verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
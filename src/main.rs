@@ -1,11 +1,24 @@
+mod cache;
+mod dpop;
 mod entity;
 mod filters;
 mod handlers;
+mod issuer;
+mod keys;
+mod login;
+mod normalize;
+mod provider;
 mod response;
+mod routes;
+mod signer;
 
 #[cfg(feature = "builtin-casbin")]
 mod actions;
 #[cfg(feature = "builtin-casbin")]
+mod api_keys;
+#[cfg(feature = "builtin-casbin")]
+mod mtls;
+#[cfg(feature = "builtin-casbin")]
 mod adapter;
 #[cfg(feature = "builtin-casbin")]
 mod error;
@@ -13,18 +26,33 @@ mod error;
 #[cfg(feature = "builtin-casbin")]
 use adapter::SqlxAdapter;
 #[cfg(feature = "builtin-casbin")]
-use casbin::DefaultModel;
+use casbin::{CoreApi, DefaultModel, Enforcer};
+#[cfg(feature = "builtin-casbin")]
+use entity::PolicyVersion;
+#[cfg(feature = "builtin-casbin")]
+use log::error;
 #[cfg(feature = "builtin-casbin")]
 use once_cell::sync::OnceCell;
+#[cfg(feature = "builtin-casbin")]
+use std::collections::HashSet;
+#[cfg(feature = "builtin-casbin")]
+use tokio::sync::RwLock;
 
+use cache::{BasicAuthCache, IntrospectCache, ReplayCache, RevokedTokens};
 use chrono::Local;
 use clap::Parser;
-use entity::Config;
+use entity::{AuthStrategy, Config, IssuerConfig, ProviderKind};
+use issuer::Issuer;
+use jsonwebtoken::{jwk::JwkSet, Algorithm};
 use lazy_static::lazy_static;
-use log::info;
+use login::{LoginStore, SessionStore};
+use log::{info, warn};
 use pretty_env_logger::env_logger;
 use reqwest::Client;
-use std::{io::Write, net::SocketAddr};
+use response::DiscoveryResponse;
+use signer::Signer;
+use std::{io::Write, net::SocketAddr, time::Duration};
+use warp::http::header::HeaderName;
 use warp::Filter;
 
 #[derive(Parser, Debug)]
@@ -44,11 +72,40 @@ struct Args {
 static MODEL: OnceCell<DefaultModel> = OnceCell::new();
 #[cfg(feature = "builtin-casbin")]
 static ADAPTER: OnceCell<SqlxAdapter> = OnceCell::new();
+#[cfg(feature = "builtin-casbin")]
+static ENFORCER: OnceCell<RwLock<Enforcer>> = OnceCell::new();
 
 lazy_static! {
     static ref CONFIG: Config = load_conf();
     static ref ARGS: Args = Args::parse();
     static ref CLIENT: Client = Client::new();
+    static ref ISSUERS: Vec<Issuer> = load_issuers();
+    // Users have 10 minutes to finish a login
+    static ref CSRF_COOKIE_NAME: String = format!("{}_csrf", handlers::session_cookie_name());
+    static ref LOGINS: LoginStore = LoginStore::new(login::LOGIN_TTL, 10000);
+    static ref SESSIONS: SessionStore = SessionStore::new(CONFIG.session_capacity.unwrap_or(10000));
+    static ref REVOKED_TOKENS: RevokedTokens = RevokedTokens::default();
+    static ref SIGNER: Option<Signer> = CONFIG.internal_jwt_key.as_deref().map(|pem| {
+        Signer::load(pem).unwrap_or_else(|err| panic!("Load internal jwt key failed: {}", err))
+    });
+    static ref DPOP_PROOFS: ReplayCache = ReplayCache::new(
+        100000,
+        CONFIG.dpop_max_proofs_per_key.unwrap_or(10000),
+    );
+    static ref BASIC_AUTH_CACHE: BasicAuthCache = BasicAuthCache::new(
+        CONFIG.basic_auth_cache_ttl.unwrap_or(60),
+        CONFIG.basic_auth_max_failures.unwrap_or(5),
+        CONFIG.basic_auth_lockout.unwrap_or(300),
+        10000,
+    );
+    static ref INTROSPECT_CACHE: IntrospectCache = IntrospectCache::new(
+        // Periodic introspection rechecks a token once its cached result expires
+        match CONFIG.auth_strategy.unwrap_or_default() {
+            AuthStrategy::LocalPeriodicIntrospect => CONFIG.introspect_interval.unwrap_or(60),
+            _ => CONFIG.introspect_cache_ttl.unwrap_or(30),
+        },
+        CONFIG.introspect_cache_capacity.unwrap_or(10000),
+    );
 }
 
 /// initialize logger
@@ -71,37 +128,339 @@ fn init_log() {
 
 /// load configuration
 fn load_conf() -> Config {
-    config::Config::builder()
+    let conf = config::Config::builder()
         .add_source(config::File::with_name(&ARGS.config))
         .build()
         .unwrap()
         .try_deserialize::<Config>()
-        .map(|c| Config {
-            jwt_pub_key: c.jwt_pub_key.replace("CERTIFICATE", "PUBLIC KEY"),
-            ..c
-        })
-        .unwrap()
+        .unwrap();
+    // Subjects of different issuers must never collide, or users of one issuer
+    // could be granted the policies of another one
+    let mut prefixes = vec![conf.subject_prefix.clone().unwrap_or_default()];
+    for issuer in conf.issuers.iter().flatten() {
+        let iss = match &issuer.iss {
+            Some(iss) => iss,
+            None => panic!("Every issuer in issuers should be configured with iss"),
+        };
+        let prefix = issuer.subject_prefix.clone().unwrap_or_default();
+        if prefix.is_empty() {
+            panic!("Issuer {} should be configured with a non-empty subject_prefix", iss)
+        }
+        if let Some(other) = prefixes
+            .iter()
+            .find(|other| !other.is_empty() && (other.starts_with(&prefix) || prefix.starts_with(other.as_str())))
+        {
+            panic!("subject_prefix \"{}\" of issuer {} overlaps with \"{}\"", prefix, iss, other)
+        }
+        prefixes.push(prefix);
+    }
+    for name in conf.identity_headers.iter().flat_map(|headers| headers.values()) {
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            panic!("Invalid identity header name \"{}\"", name)
+        }
+    }
+    conf
+}
+
+/// build the default issuer from the top-level configuration, followed by the other issuers
+fn load_issuers() -> Vec<Issuer> {
+    let algorithms = CONFIG
+        .jwt_algorithms
+        .clone()
+        .unwrap_or_else(|| vec![Algorithm::RS256, Algorithm::ES256]);
+    if algorithms.is_empty() {
+        panic!("At least one jwt algorithm should be allowed")
+    }
+    for alg in &algorithms {
+        if !matches!(
+            alg,
+            Algorithm::RS256 | Algorithm::RS512 | Algorithm::ES256 | Algorithm::PS256
+        ) {
+            panic!("Unsupported jwt algorithm {:?}", alg)
+        }
+    }
+    let leeway = CONFIG.jwt_leeway.unwrap_or(60);
+    let warning_days = CONFIG.cert_expiry_warning_days.unwrap_or(30);
+
+    let default = IssuerConfig {
+        iss: None,
+        provider: CONFIG.provider,
+        discovery: CONFIG.discovery,
+        username_claim: CONFIG.username_claim.clone(),
+        endpoint: CONFIG.endpoint.clone(),
+        client_id: CONFIG.client_id.clone(),
+        client_secret: CONFIG.client_secret.clone(),
+        jwt_pub_key: CONFIG.jwt_pub_key.clone(),
+        jwks_url: CONFIG.jwks_url.clone(),
+        jwt_audiences: CONFIG.jwt_audiences.clone(),
+        service_clients: CONFIG.service_clients.clone(),
+        org_name: CONFIG.org_name.clone(),
+        #[cfg(not(feature = "builtin-casbin"))]
+        permission_name: CONFIG.permission_name.clone(),
+        subject_prefix: CONFIG.subject_prefix.clone(),
+    };
+    let mut issuers = vec![Issuer::new(
+        default,
+        CONFIG.jwt_issuers.as_deref(),
+        &algorithms,
+        leeway,
+        warning_days,
+    )];
+    for conf in CONFIG.issuers.iter().flatten() {
+        let iss = conf.iss.clone().into_iter().collect::<Vec<String>>();
+        issuers.push(Issuer::new(
+            conf.clone(),
+            Some(&iss),
+            &algorithms,
+            leeway,
+            warning_days,
+        ));
+    }
+    issuers
+}
+
+/// fetch token signing keys from the jwks endpoint
+async fn fetch_jwks(url: &str) -> Result<JwkSet, reqwest::Error> {
+    CLIENT
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<JwkSet>()
+        .await
+}
+
+/// read endpoints of every issuer from its openid configuration.
+/// Casdoor falls back to its well-known api paths if discovery fails.
+async fn discover() {
+    for issuer in ISSUERS.iter() {
+        if !issuer.conf.discovery.unwrap_or(true) {
+            continue;
+        }
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer.conf.endpoint.trim_end_matches('/')
+        );
+        let resp = match CLIENT.get(&url).send().await {
+            Ok(resp) => resp.error_for_status(),
+            Err(err) => Err(err),
+        };
+        let metadata = match resp {
+            Ok(resp) => resp.json::<DiscoveryResponse>().await,
+            Err(err) => Err(err),
+        };
+        match metadata {
+            Ok(metadata) => {
+                issuer.set_endpoints(metadata.into());
+                info!("Endpoints of issuer {} discovered from {}", issuer.name(), url);
+            }
+            Err(err) if issuer.conf.provider.unwrap_or_default() == ProviderKind::Casdoor => {
+                warn!("Discover endpoints from {} failed, use default ones: {}", url, err)
+            }
+            Err(err) => panic!("Discover endpoints from {} failed: {}", url, err),
+        }
+    }
+}
+
+/// load token signing keys of every issuer, keys from jwks endpoints are required at startup
+async fn load_keys() {
+    for issuer in ISSUERS.iter() {
+        match issuer.jwks_url() {
+            Some(url) => {
+                let set = fetch_jwks(&url).await.expect("Fetch jwks failed");
+                info!(
+                    "{} signing keys of issuer {} loaded from {}",
+                    issuer.keys.update(&set),
+                    issuer.name(),
+                    url
+                );
+            }
+            None if issuer.conf.jwt_pub_key.is_none() => panic!(
+                "Either jwt_pub_key or jwks_url should be configured for issuer {}",
+                issuer.name()
+            ),
+            None => {}
+        }
+    }
 }
 
-/// load permission model and policy adapter
+/// refresh token signing keys of the issuer from its jwks endpoint periodically
+async fn watch_jwks(issuer: &Issuer, url: String, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match fetch_jwks(&url).await {
+            Ok(set) => info!(
+                "{} signing keys of issuer {} refreshed from {}",
+                issuer.keys.update(&set),
+                issuer.name(),
+                url
+            ),
+            Err(err) => warn!("Refresh jwks failed, keep using known keys: {}", err),
+        }
+    }
+}
+
+/// load permission model, policy adapter and the shared enforcer,
+/// return the version of the loaded policies
 #[cfg(feature = "builtin-casbin")]
-async fn load_perm() {
+async fn load_perm() -> PolicyVersion {
     let model = DefaultModel::from_file(&ARGS.model).await.unwrap();
-    if let Err(_) = MODEL.set(model) {
+    if MODEL.set(model).is_err() {
         panic!("Load permission model into memory failed")
     }
     let adapter = SqlxAdapter::new(&CONFIG.casdoor_db, 8).await.unwrap();
-    if let Err(_) = ADAPTER.set(adapter) {
+    let version = adapter.policy_version().await.unwrap();
+    if ADAPTER.set(adapter).is_err() {
         panic!("Load permission adapter into memory failed")
     }
+    let enforcer = build_enforcer().await.unwrap();
+    info!("{} policies loaded", policy_set(&enforcer).len());
+    if ENFORCER.set(RwLock::new(enforcer)).is_err() {
+        panic!("Load enforcer into memory failed")
+    }
+    version
+}
+
+/// build a new enforcer from the loaded model and adapter,
+/// reading all policies from the database
+#[cfg(feature = "builtin-casbin")]
+async fn build_enforcer() -> casbin::Result<Enforcer> {
+    let model = MODEL.get().expect("Permission model not loaded");
+    let adapter = ADAPTER.get().expect("Permission adapter not loaded");
+    Enforcer::new(model.clone(), adapter.clone()).await
+}
+
+/// collect every policy and grouping rule held by the enforcer as `[ptype, v0, v1, ...]`
+#[cfg(feature = "builtin-casbin")]
+fn policy_set(enforcer: &Enforcer) -> HashSet<Vec<String>> {
+    let mut rules = HashSet::new();
+    for (sec, ast_map) in enforcer.get_model().get_model() {
+        if sec != "p" && sec != "g" {
+            continue;
+        }
+        for (ptype, ast) in ast_map {
+            for rule in ast.get_policy() {
+                let mut line = vec![ptype.clone()];
+                line.extend(rule.iter().cloned());
+                rules.insert(line);
+            }
+        }
+    }
+    rules
+}
+
+/// reload policies into the shared enforcer and return (added, removed) rule counts.
+/// The new enforcer is built first and then swapped in,
+/// so that inbound requests are never blocked by the database query.
+#[cfg(feature = "builtin-casbin")]
+async fn reload_perm() -> casbin::Result<(usize, usize)> {
+    let enforcer = build_enforcer().await?;
+    let new_rules = policy_set(&enforcer);
+    let shared = ENFORCER.get().expect("Enforcer not loaded");
+    let mut current = shared.write().await;
+    let old_rules = policy_set(&current);
+    *current = enforcer;
+    drop(current);
+    Ok((
+        new_rules.difference(&old_rules).count(),
+        old_rules.difference(&new_rules).count(),
+    ))
+}
+
+/// poll the policy table and reload the shared enforcer when its version changes
+#[cfg(feature = "builtin-casbin")]
+async fn watch_policy_changes(mut version: PolicyVersion, period: Duration) {
+    let adapter = ADAPTER.get().expect("Permission adapter not loaded");
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let latest = match adapter.policy_version().await {
+            Ok(v) => v,
+            Err(err) => {
+                error!("Check policy version failed: {}", err);
+                continue;
+            }
+        };
+        if latest == version {
+            continue;
+        }
+        match reload_perm().await {
+            Ok((added, removed)) => {
+                info!(
+                    "Policy change detected, reloaded: {} added, {} removed",
+                    added, removed
+                );
+                version = latest;
+            }
+            Err(err) => error!("Reload policies failed: {}", err),
+        }
+    }
+}
+
+/// reload policies whenever the process receives SIGHUP
+#[cfg(all(feature = "builtin-casbin", unix))]
+async fn watch_reload_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(err) => {
+            error!("Listen for SIGHUP failed: {}", err);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match reload_perm().await {
+            Ok((added, removed)) => {
+                info!("Policies reloaded: {} added, {} removed", added, removed)
+            }
+            Err(err) => error!("Reload policies failed: {}", err),
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
     init_log();
 
+    discover().await;
+    load_keys().await;
+    // Refresh every 5 minutes by default, 0 disables refreshing
+    match CONFIG.jwks_refresh_interval.unwrap_or(300) {
+        0 => info!("Jwks refreshing disabled"),
+        secs => {
+            for issuer in ISSUERS.iter() {
+                if let Some(url) = issuer.jwks_url() {
+                    tokio::spawn(watch_jwks(issuer, url, Duration::from_secs(secs)));
+                }
+            }
+        }
+    }
+
     #[cfg(feature = "builtin-casbin")]
-    load_perm().await;
+    {
+        let version = load_perm().await;
+        // Poll every 10 seconds by default, 0 disables change detection
+        match CONFIG.policy_poll_interval.unwrap_or(10) {
+            0 => info!("Policy change detection disabled"),
+            secs => {
+                tokio::spawn(watch_policy_changes(version, Duration::from_secs(secs)));
+            }
+        }
+        #[cfg(unix)]
+        tokio::spawn(watch_reload_signal());
+    }
+
+    if let Some(signer) = SIGNER.as_ref() {
+        info!("Internal tokens are signed by key {}", signer.kid());
+    }
 
     let log = warp::log::custom(|info| {
         info!("{} {}, {}", info.method(), info.path(), info.status());
@@ -111,6 +470,14 @@ async fn main() {
 
     let route = filters::authenticate()
         .or(filters::login())
+        .or(filters::callback())
+        .or(filters::refresh())
+        .or(filters::logout())
+        .or(filters::jwks());
+    // Api keys are stored in the database of the builtin casbin
+    #[cfg(feature = "builtin-casbin")]
+    let route = route.or(filters::api_keys());
+    let route = route
         .recover(handlers::err_handle)
         .with(log)
         .with(cors);
@@ -0,0 +1,135 @@
+use percent_encoding::percent_decode_str;
+use x509_parser::extensions::GeneralName;
+use x509_parser::pem::parse_x509_pem;
+use x509_parser::prelude::{FromDer, X509Certificate};
+use x509_parser::x509::X509Name;
+
+use crate::entity::CertField;
+
+/// Identity of the client certificate forwarded by the proxy.
+/// The header holds either the certificate, base64 DER or url-encoded PEM,
+/// or only its subject DN, which does not carry the SAN.
+pub fn identity(value: &str, field: CertField) -> Result<String, String> {
+    let value = value.trim();
+    let der = if value.starts_with("-----BEGIN") {
+        let pem = percent_decode_str(value).collect::<Vec<u8>>();
+        parse_x509_pem(&pem)
+            .map_err(|err| format!("Malformed client certificate: {}", err))?
+            .1
+            .contents
+    } else if value.trim_end_matches('=').contains('=') {
+        // Base64 only has "=" as padding, so this is a DN
+        return dn_identity(value, field);
+    } else {
+        base64::decode(value).map_err(|err| format!("Malformed client certificate: {}", err))?
+    };
+    let (_, cert) = X509Certificate::from_der(&der)
+        .map_err(|err| format!("Malformed client certificate: {}", err))?;
+    if !cert.validity().is_valid() {
+        return Err(format!(
+            "Client certificate is only valid from {} to {}",
+            cert.validity().not_before,
+            cert.validity().not_after
+        ));
+    }
+    let found = match field {
+        CertField::Dn => Some(cert.subject().to_string()),
+        CertField::Cn => common_name(cert.subject()),
+        CertField::SanDns | CertField::SanEmail | CertField::SanUri => cert
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .and_then(|san| {
+                san.value.general_names.iter().find_map(|name| match (field, name) {
+                    (CertField::SanDns, GeneralName::DNSName(name))
+                    | (CertField::SanEmail, GeneralName::RFC822Name(name))
+                    | (CertField::SanUri, GeneralName::URI(name)) => Some(name.to_string()),
+                    _ => None,
+                })
+            }),
+    };
+    found
+        .filter(|found| !found.is_empty())
+        .ok_or_else(|| format!("Client certificate has no {:?}", field))
+}
+
+fn common_name(name: &X509Name) -> Option<String> {
+    name.iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(str::to_string)
+}
+
+/// Identity of a subject DN such as "CN=billing,O=Akashic".
+fn dn_identity(dn: &str, field: CertField) -> Result<String, String> {
+    match field {
+        CertField::Dn => Ok(dn.to_string()),
+        CertField::Cn => dn
+            .split(',')
+            .filter_map(|rdn| rdn.trim().split_once('='))
+            .find(|(attr, _)| attr.trim().eq_ignore_ascii_case("CN"))
+            .map(|(_, cn)| cn.trim().to_string())
+            .filter(|cn| !cn.is_empty())
+            .ok_or_else(|| format!("Client subject \"{}\" has no CN", dn)),
+        _ => Err(format!("Client subject \"{}\" carries no {:?}", dn, field)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Self-signed "CN=billing, O=Akashic" valid until 2120 with DNS, email and URI SANs
+    const VALID: &str = "MIIBhzCCASygAwIBAgIBATAKBggqhkjOPQQDAjAkMRAwDgYDVQQDDAdiaWxsaW5nMRAwDgYDVQQKDAdBa2FzaGljMCAXDTIwMDEwMTAwMDAwMFoYDzIxMjAwMTAxMDAwMDAwWjAkMRAwDgYDVQQDDAdiaWxsaW5nMRAwDgYDVQQKDAdBa2FzaGljMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEeRio5uu03pJQ7SFy/Raafo0bpEkRx+v1ovYGyE8e2yh9uhHQWNFvhxwLiJ07I1DUDLsPJpcxrX2z6ZQakXHpdKNNMEswSQYDVR0RBEIwQIILYmlsbGluZy5zdmOBE2JpbGxpbmdAZXhhbXBsZS5jb22GHHNwaWZmZTovL2V4YW1wbGUuY29tL2JpbGxpbmcwCgYIKoZIzj0EAwIDSQAwRgIhAIR+jpbPf/yLBxwD+ySJfeBduK1cGjBwuyLEYmTf+MinAiEAjK3eOWbL+iTH1jlRXiDWGaGwn31NcIb7b44rvk/i4UY=";
+    /// The same certificate valid in 2020 only
+    const EXPIRED: &str = "MIIBhDCCASqgAwIBAgIBATAKBggqhkjOPQQDAjAkMRAwDgYDVQQDDAdiaWxsaW5nMRAwDgYDVQQKDAdBa2FzaGljMB4XDTIwMDEwMTAwMDAwMFoXDTIxMDEwMTAwMDAwMFowJDEQMA4GA1UEAwwHYmlsbGluZzEQMA4GA1UECgwHQWthc2hpYzBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABHkYqObrtN6SUO0hcv0Wmn6NG6RJEcfr9aL2BshPHtsofboR0FjRb4ccC4idOyNQ1Ay7DyaXMa19s+mUGpFx6XSjTTBLMEkGA1UdEQRCMECCC2JpbGxpbmcuc3ZjgRNiaWxsaW5nQGV4YW1wbGUuY29thhxzcGlmZmU6Ly9leGFtcGxlLmNvbS9iaWxsaW5nMAoGCCqGSM49BAMCA0gAMEUCID5vIm5B+ct9ImyXxGVSi5q8pGtIz6aEiyJq/g5rMkPxAiEAssYrd0xrGhMj28ySdwH7ReDNajhgJyI2zQARWpHpCbo=";
+    /// The same certificate valid until 2120 without SAN
+    const NO_SAN: &str = "MIIBNTCB3aADAgECAgEBMAoGCCqGSM49BAMCMCQxEDAOBgNVBAMMB2JpbGxpbmcxEDAOBgNVBAoMB0FrYXNoaWMwIBcNMjAwMTAxMDAwMDAwWhgPMjEyMDAxMDEwMDAwMDBaMCQxEDAOBgNVBAMMB2JpbGxpbmcxEDAOBgNVBAoMB0FrYXNoaWMwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAR5GKjm67TeklDtIXL9Fpp+jRukSRHH6/Wi9gbITx7bKH26EdBY0W+HHAuInTsjUNQMuw8mlzGtfbPplBqRcel0MAoGCCqGSM49BAMCA0cAMEQCIBibjOeA1eGgbLDQbUJnWcW9DTdjp3WBaksq6r/AnXkbAiApyIv44nID7cnNSBeGDZWN9Lb+oRyuqh5xWy6i0sL3DQ==";
+
+    /// Url-encoded PEM as Caddy forwards it
+    fn pem(der_base64: &str) -> String {
+        let lines = der_base64
+            .as_bytes()
+            .chunks(64)
+            .map(|line| std::str::from_utf8(line).unwrap())
+            .collect::<Vec<_>>()
+            .join("%0A");
+        format!("-----BEGIN%20CERTIFICATE-----%0A{}%0A-----END%20CERTIFICATE-----%0A", lines)
+    }
+
+    #[test]
+    fn certificate_fields() {
+        assert_eq!(identity(VALID, CertField::Cn).unwrap(), "billing");
+        assert_eq!(identity(VALID, CertField::Dn).unwrap(), "CN=billing, O=Akashic");
+        assert_eq!(identity(VALID, CertField::SanDns).unwrap(), "billing.svc");
+        assert_eq!(identity(VALID, CertField::SanEmail).unwrap(), "billing@example.com");
+        assert_eq!(identity(VALID, CertField::SanUri).unwrap(), "spiffe://example.com/billing");
+        assert!(identity(NO_SAN, CertField::SanDns).unwrap_err().contains("has no"));
+    }
+
+    #[test]
+    fn certificate_encodings() {
+        assert_eq!(identity(&pem(VALID), CertField::Cn).unwrap(), "billing");
+        assert_eq!(identity(&format!(" {}\n", VALID), CertField::Cn).unwrap(), "billing");
+        assert!(identity("bm90IGEgY2VydGlmaWNhdGU", CertField::Cn).unwrap_err().starts_with("Malformed"));
+        assert!(identity("-----BEGIN%20CERTIFICATE-----", CertField::Cn).unwrap_err().starts_with("Malformed"));
+    }
+
+    #[test]
+    fn expired_certificate() {
+        let err = identity(EXPIRED, CertField::Cn).unwrap_err();
+        assert!(err.contains("only valid from"), "{}", err);
+        assert!(identity(&pem(EXPIRED), CertField::Dn).is_err());
+    }
+
+    #[test]
+    fn subject_dn() {
+        assert_eq!(identity("CN=billing,O=Akashic", CertField::Cn).unwrap(), "billing");
+        assert_eq!(identity("O=Akashic, cn = billing ", CertField::Cn).unwrap(), "billing");
+        assert_eq!(identity("CN=billing,O=Akashic", CertField::Dn).unwrap(), "CN=billing,O=Akashic");
+        assert!(identity("O=Akashic,OU=Payments", CertField::Cn).unwrap_err().contains("no CN"));
+        assert!(identity("O=Akashic,CN=", CertField::Cn).is_err());
+        // SANs are not part of the DN
+        assert!(identity("CN=billing", CertField::SanDns).unwrap_err().contains("carries no"));
+    }
+}
@@ -0,0 +1,444 @@
+use crate::entity::PathStep;
+
+/// Steps of the normalization pipeline, always run in this order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    /// Decode percent-encoded characters except those with a meaning in paths
+    pub decode: bool,
+    /// Collapse repeated slashes
+    pub merge_slashes: bool,
+    /// Resolve `.` and `..` segments
+    pub resolve_dots: bool,
+    /// Remove the trailing slash of paths other than `/`
+    pub strip_trailing_slash: bool,
+    /// Lowercase ASCII letters
+    pub lowercase: bool,
+    /// Reject ambiguous paths instead of keeping them as they are
+    pub strict: bool,
+}
+
+impl Options {
+    pub fn new(steps: &[PathStep], strict: bool) -> Self {
+        Self {
+            decode: steps.contains(&PathStep::Decode),
+            merge_slashes: steps.contains(&PathStep::MergeSlashes),
+            resolve_dots: steps.contains(&PathStep::ResolveDots),
+            strip_trailing_slash: steps.contains(&PathStep::StripTrailingSlash),
+            lowercase: steps.contains(&PathStep::Lowercase),
+            strict,
+        }
+    }
+}
+
+/// Whether a decoded byte has to stay encoded, as decoding it would change the path structure.
+fn reserved(byte: u8) -> bool {
+    matches!(byte, b'/' | b'\\' | b'%' | b'?' | b'#') || byte.is_ascii_control()
+}
+
+/// Whether the decoded byte makes the encoded path mean different things to different servers.
+/// Encoded slashes are split by some upstreams, and a decoded `%` means double encoding.
+fn ambiguous(byte: u8) -> bool {
+    matches!(byte, b'/' | b'\\' | b'%') || byte.is_ascii_control()
+}
+
+fn unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~')
+}
+
+fn hex(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+fn push_encoded(out: &mut Vec<u8>, byte: u8) {
+    out.extend_from_slice(format!("%{:02X}", byte).as_bytes());
+}
+
+/// Decode the escapes of the path, leaving the reserved ones encoded in uppercase hex.
+fn decode(path: &str, opts: &Options) -> Result<String, String> {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes[i];
+        let escaped = match byte {
+            b'%' => match (bytes.get(i + 1).and_then(|&b| hex(b)), bytes.get(i + 2).and_then(|&b| hex(b))) {
+                (Some(high), Some(low)) => Some(high << 4 | low),
+                _ if opts.strict => return Err("Path has an invalid percent-encoding".to_string()),
+                // A literal percent sign
+                _ => {
+                    push_encoded(&mut out, b'%');
+                    i += 1;
+                    continue;
+                }
+            },
+            _ => None,
+        };
+        match escaped {
+            Some(decoded) => {
+                i += 3;
+                if opts.strict && ambiguous(decoded) {
+                    return Err(format!("Path has an ambiguous encoded character %{:02X}", decoded));
+                }
+                if reserved(decoded) {
+                    push_encoded(&mut out, decoded);
+                } else if opts.decode {
+                    out.push(if opts.lowercase { decoded.to_ascii_lowercase() } else { decoded });
+                } else if opts.strict && unreserved(decoded) {
+                    return Err(format!("Path has an encoded unreserved character %{:02X}", decoded));
+                } else {
+                    push_encoded(&mut out, decoded);
+                }
+            }
+            None => {
+                i += 1;
+                if byte.is_ascii_control() {
+                    if opts.strict {
+                        return Err("Path has a control character".to_string());
+                    }
+                    push_encoded(&mut out, byte);
+                } else if byte == b'\\' && opts.strict {
+                    // Taken as a slash by some servers
+                    return Err("Path has a backslash".to_string());
+                } else {
+                    out.push(if opts.lowercase { byte.to_ascii_lowercase() } else { byte });
+                }
+            }
+        }
+    }
+
+    match String::from_utf8(out) {
+        Ok(path) => Ok(path),
+        Err(_) if opts.strict => Err("Path is not valid UTF-8 once decoded".to_string()),
+        // Keep every non-ASCII byte encoded
+        Err(err) => {
+            let mut out = Vec::new();
+            for byte in err.into_bytes() {
+                match byte.is_ascii() {
+                    true => out.push(byte),
+                    false => push_encoded(&mut out, byte),
+                }
+            }
+            Ok(String::from_utf8(out).unwrap_or_default())
+        }
+    }
+}
+
+/// Normalize the path of a request uri before it is matched against policies.
+/// The query and fragment are removed, then the enabled steps run in a fixed order:
+/// decoding, merging slashes, resolving dot segments, stripping the trailing slash
+/// and lowercasing. Strict mode rejects paths which may be understood differently
+/// by the upstream, such as encoded slashes, double encoding, backslashes, dot segments
+/// with parameters and `..` escaping the root, as well as dot segments and empty segments
+/// which the enabled steps leave in place.
+pub fn path(uri: &str, opts: &Options) -> Result<String, String> {
+    let raw = uri.split(['?', '#']).next().unwrap_or_default();
+    let raw = match raw.strip_prefix('/') {
+        Some(raw) => raw,
+        None if opts.strict && !raw.is_empty() => return Err("Path does not start with /".to_string()),
+        None => raw,
+    };
+    let decoded = decode(raw, opts)?;
+
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    let count = decoded.split('/').count();
+    for (index, segment) in decoded.split('/').enumerate() {
+        let last = index + 1 == count;
+        trailing_slash = false;
+        // Parameters such as "..;jsessionid" are dropped by some servers before resolving
+        let name = segment.split(';').next().unwrap_or_default();
+        if opts.strict && segment.contains(';') && matches!(name, "." | "..") {
+            return Err("Path has a dot segment with parameters".to_string());
+        }
+        match segment {
+            "" if last => trailing_slash = index > 0,
+            "" if opts.merge_slashes => {}
+            "" if opts.strict => return Err("Path has an empty segment".to_string()),
+            "." | ".." if !opts.resolve_dots && opts.strict => {
+                return Err("Path has a dot segment".to_string())
+            }
+            "." if opts.resolve_dots => trailing_slash = last,
+            ".." if opts.resolve_dots => {
+                if segments.pop().is_none() && opts.strict {
+                    return Err("Path escapes the root".to_string());
+                }
+                trailing_slash = last;
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    if opts.strip_trailing_slash {
+        // Empty segments kept at the end are trailing slashes as well
+        while segments.last() == Some(&"") {
+            segments.pop();
+        }
+    }
+    let mut path = format!("/{}", segments.join("/"));
+    if trailing_slash && !opts.strip_trailing_slash && path != "/" {
+        path.push('/');
+    }
+    Ok(path)
+}
+
+/// Whether the normalized path is the prefix itself or below it, matching whole segments,
+/// so that "/api/payments" does not cover "/api/payments-public".
+pub fn under(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    path.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const DEFAULT: Options = Options {
+        decode: true,
+        merge_slashes: true,
+        resolve_dots: true,
+        strip_trailing_slash: false,
+        lowercase: false,
+        strict: true,
+    };
+
+    fn options() -> impl Strategy<Value = Options> {
+        (any::<[bool; 6]>()).prop_map(|flags| Options {
+            decode: flags[0],
+            merge_slashes: flags[1],
+            resolve_dots: flags[2],
+            strip_trailing_slash: flags[3],
+            lowercase: flags[4],
+            strict: flags[5],
+        })
+    }
+
+    /// Segments of characters which are never changed by the normalization.
+    fn plain_segment() -> impl Strategy<Value = String> {
+        "[a-zA-Z0-9_~-][a-zA-Z0-9._~-]{0,7}".prop_filter("dot segment", |s| s != "." && s != "..")
+    }
+
+    /// Paths built from the characters that matter to the normalization.
+    fn tricky_uri() -> impl Strategy<Value = String> {
+        proptest::collection::vec(
+            prop_oneof![
+                Just("/".to_string()),
+                Just("//".to_string()),
+                Just(".".to_string()),
+                Just("..".to_string()),
+                Just(";".to_string()),
+                Just("\\".to_string()),
+                Just("?".to_string()),
+                Just("#".to_string()),
+                Just("%".to_string()),
+                "%[0-9a-fA-F]{2}",
+                "%[0-9a-fA-F]",
+                "[a-zA-Z]{1,3}",
+                "[\\x00-\\x7f]",
+            ],
+            0..16,
+        )
+        .prop_map(|parts| parts.concat())
+    }
+
+    fn encode_all(segment: &str) -> String {
+        segment.bytes().map(|byte| format!("%{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn default_pipeline() {
+        let cases = [
+            ("/", "/"),
+            ("", "/"),
+            ("/api/users?id=1#top", "/api/users"),
+            ("/api//users", "/api/users"),
+            ("//api/users", "/api/users"),
+            ("/api/./users", "/api/users"),
+            ("/api/v1/../users", "/api/users"),
+            ("/api/users/", "/api/users/"),
+            ("/api/users/.", "/api/users/"),
+            ("/api/%75sers", "/api/users"),
+            ("/api/%2e%2E/admin", "/admin"),
+            ("/api/%3f/%23", "/api/%3F/%23"),
+            ("/api/%E4%B8%AD", "/api/中"),
+            ("/API/Users", "/API/Users"),
+        ];
+        for (uri, expected) in cases {
+            assert_eq!(path(uri, &DEFAULT).as_deref(), Ok(expected), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn strict_rejections() {
+        let cases = [
+            "api/users",
+            "/api%2Fadmin",
+            "/api%5cadmin",
+            "/api\\admin",
+            "/api/%252e%252e/admin",
+            "/api/%zz",
+            "/api/%4",
+            "/api/%00",
+            "/api/%FF",
+            "/..",
+            "/api/../..",
+            "/api/..;/admin",
+            "/api/.;x/admin",
+        ];
+        for uri in cases {
+            assert!(path(uri, &DEFAULT).is_err(), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn segment_prefixes() {
+        assert!(under("/api/payments", "/api/payments"));
+        assert!(under("/api/payments/1", "/api/payments"));
+        assert!(under("/api/payments/1", "/api/payments/"));
+        assert!(under("/api/payments", "/api/payments/"));
+        assert!(!under("/api/payments-public", "/api/payments"));
+        assert!(!under("/api/paymentsx/1", "/api/payments/"));
+        assert!(!under("/api", "/api/payments"));
+        assert!(under("/anything", "/"));
+    }
+
+    #[test]
+    fn optional_steps() {
+        let opts = Options {
+            strip_trailing_slash: true,
+            lowercase: true,
+            ..DEFAULT
+        };
+        assert_eq!(path("/API/Users/", &opts).as_deref(), Ok("/api/users"));
+        assert_eq!(path("/API/%55sers/.", &opts).as_deref(), Ok("/api/users"));
+        assert_eq!(path("/%2F", &Options { strict: false, ..opts }).as_deref(), Ok("/%2F"));
+
+        let opts = Options {
+            merge_slashes: false,
+            resolve_dots: false,
+            ..DEFAULT
+        };
+        assert!(path("/api//users", &opts).is_err());
+        assert!(path("/api/../users", &opts).is_err());
+        let opts = Options { strict: false, ..opts };
+        assert_eq!(path("/api//users/../x", &opts).as_deref(), Ok("/api//users/../x"));
+
+        let opts = Options { decode: false, ..DEFAULT };
+        assert!(path("/api/%75sers", &opts).is_err());
+        assert_eq!(path("/api/%e4%b8%ad", &opts).as_deref(), Ok("/api/%E4%B8%AD"));
+        assert_eq!(path("/api/%75sers", &Options { strict: false, ..opts }).as_deref(), Ok("/api/%75sers"));
+    }
+
+    #[test]
+    fn lenient_mode() {
+        let opts = Options { strict: false, ..DEFAULT };
+        assert_eq!(path("api/users", &opts).as_deref(), Ok("/api/users"));
+        assert_eq!(path("/../../admin", &opts).as_deref(), Ok("/admin"));
+        assert_eq!(path("/api/%zz", &opts).as_deref(), Ok("/api/%25zz"));
+        assert_eq!(path("/api%2fadmin", &opts).as_deref(), Ok("/api%2Fadmin"));
+        assert_eq!(path("/api/%FF", &opts).as_deref(), Ok("/api/%FF"));
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(2000))]
+
+        #[test]
+        fn never_panics(uri in "\\PC*", opts in options()) {
+            let _ = path(&uri, &opts);
+        }
+
+        #[test]
+        fn never_panics_on_tricky_paths(uri in tricky_uri(), opts in options()) {
+            let _ = path(&uri, &opts);
+        }
+
+        #[test]
+        fn idempotent(uri in tricky_uri(), opts in options()) {
+            if let Ok(once) = path(&uri, &opts) {
+                prop_assert_eq!(path(&once, &opts), Ok(once.clone()));
+            }
+        }
+
+        #[test]
+        fn canonical_output(uri in tricky_uri(), opts in options()) {
+            if let Ok(normalized) = path(&uri, &opts) {
+                prop_assert!(normalized.starts_with('/'));
+                prop_assert!(!normalized.contains(['?', '#']));
+                prop_assert!(!normalized.bytes().any(|byte| byte.is_ascii_control()));
+                // Every percent sign starts an escape in uppercase hex
+                let bytes = normalized.as_bytes();
+                for (i, _) in normalized.match_indices('%') {
+                    let escape = bytes.get(i + 1..i + 3);
+                    prop_assert!(escape.is_some_and(|hex| hex.iter().all(|b| b.is_ascii_digit() || (b'A'..=b'F').contains(b))));
+                }
+                let segments = normalized[1..].split('/').collect::<Vec<&str>>();
+                let inner = &segments[..segments.len() - 1];
+                if opts.merge_slashes {
+                    prop_assert!(!inner.contains(&""));
+                }
+                if opts.resolve_dots {
+                    prop_assert!(!segments.contains(&".") && !segments.contains(&".."));
+                }
+                if opts.strip_trailing_slash {
+                    prop_assert!(normalized == "/" || !normalized.ends_with('/'));
+                }
+                if opts.lowercase {
+                    // Escapes keep their uppercase hex
+                    let letters = normalized.split('%').enumerate()
+                        .flat_map(|(i, part)| part.chars().skip(if i == 0 { 0 } else { 2 }));
+                    prop_assert!(!letters.into_iter().any(|c| c.is_ascii_uppercase()));
+                }
+            }
+        }
+
+        #[test]
+        fn strict_output_has_no_dot_segments(uri in tricky_uri(), opts in options()) {
+            let opts = Options { strict: true, ..opts };
+            if let Ok(normalized) = path(&uri, &opts) {
+                let segments = normalized.split('/').map(|segment| segment.split(';').next().unwrap_or_default());
+                prop_assert!(!segments.into_iter().any(|name| name == "." || name == ".."));
+                prop_assert!(!normalized.contains('\\') && !normalized.contains("%2F") && !normalized.contains("%25"));
+            }
+        }
+
+        #[test]
+        fn plain_paths_are_unchanged(segments in proptest::collection::vec(plain_segment(), 0..8)) {
+            let uri = format!("/{}", segments.join("/"));
+            prop_assert_eq!(path(&uri, &DEFAULT), Ok(uri.clone()));
+        }
+
+        #[test]
+        fn encoding_does_not_change_the_path(
+            segments in proptest::collection::vec(plain_segment(), 1..8),
+            encoded in any::<proptest::sample::Index>(),
+        ) {
+            let plain = format!("/{}", segments.join("/"));
+            let index = encoded.index(segments.len());
+            let mut segments = segments;
+            segments[index] = encode_all(&segments[index]);
+            prop_assert_eq!(path(&format!("/{}", segments.join("/")), &DEFAULT), Ok(plain));
+        }
+
+        #[test]
+        fn dot_segments_are_resolved(
+            prefix in proptest::collection::vec(plain_segment(), 0..4),
+            skipped in plain_segment(),
+            suffix in proptest::collection::vec(plain_segment(), 1..4),
+            dots in prop_oneof![Just(".."), Just("%2e%2e"), Just(".%2E"), Just("%2E.")],
+            query in "(\\?[a-z=&./]{0,8})?",
+        ) {
+            let expected = format!("/{}", prefix.iter().chain(&suffix).cloned().collect::<Vec<String>>().join("/"));
+            let uri = format!(
+                "/{}/{}/./{}/{}{}",
+                prefix.join("/"), skipped, dots, suffix.join("//"), query
+            );
+            prop_assert_eq!(path(&uri, &DEFAULT), Ok(expected));
+        }
+
+        #[test]
+        fn parent_of_the_root_is_rejected(depth in 0usize..4, suffix in plain_segment()) {
+            let uri = format!("{}{}/{}", "/a".repeat(depth), "/..".repeat(depth + 1), suffix);
+            prop_assert!(path(&uri, &DEFAULT).is_err());
+        }
+    }
+}
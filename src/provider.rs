@@ -0,0 +1,132 @@
+use serde_json::Value;
+
+use crate::entity::CasdoorUser;
+use crate::response::DiscoveryResponse;
+
+/// Endpoints of an identity provider.
+#[derive(Debug, Clone, Default)]
+pub struct Endpoints {
+    pub authorization: Option<String>,
+    pub token: Option<String>,
+    pub introspection: Option<String>,
+    pub revocation: Option<String>,
+    pub userinfo: Option<String>,
+    pub jwks: Option<String>,
+}
+
+impl From<DiscoveryResponse> for Endpoints {
+    fn from(resp: DiscoveryResponse) -> Self {
+        Self {
+            authorization: resp.authorization_endpoint,
+            token: resp.token_endpoint,
+            introspection: resp.introspection_endpoint,
+            revocation: resp.revocation_endpoint,
+            userinfo: resp.userinfo_endpoint,
+            jwks: resp.jwks_uri,
+        }
+    }
+}
+
+/// An identity provider issuing access tokens.
+pub trait Provider: Send + Sync {
+    /// Endpoints used when discovery is disabled or failed.
+    fn default_endpoints(&self, endpoint: &str) -> Endpoints;
+
+    /// Map the claims of a verified token into the identity used for the subject.
+    fn identity(&self, claims: Value) -> Result<CasdoorUser, String>;
+
+    /// Client id of a token issued by the client credentials grant, None for user tokens.
+    /// Such tokens are recognized by a subject equal to their client.
+    fn client(&self, claims: &Value) -> Option<String> {
+        let client = client_id(claims)?;
+        (claims.get("sub").and_then(Value::as_str) == Some(client.as_str())).then_some(client)
+    }
+}
+
+/// Client the token is issued to, by `azp`, `client_id` or a single audience.
+fn client_id(claims: &Value) -> Option<String> {
+    let aud = match claims.get("aud") {
+        Some(Value::Array(aud)) if aud.len() == 1 => aud.first(),
+        aud => aud,
+    };
+    [claims.get("azp"), claims.get("client_id"), aud]
+        .into_iter()
+        .flatten()
+        .find_map(Value::as_str)
+        .map(str::to_string)
+}
+
+/// Casdoor, whose access tokens carry the whole user entity.
+pub struct Casdoor;
+
+impl Provider for Casdoor {
+    fn default_endpoints(&self, endpoint: &str) -> Endpoints {
+        Endpoints {
+            authorization: Some(format!("{}/login/oauth/authorize", endpoint)),
+            token: Some(format!("{}/api/login/oauth/access_token", endpoint)),
+            introspection: Some(format!("{}/api/login/oauth/introspect", endpoint)),
+            revocation: None,
+            userinfo: Some(format!("{}/api/userinfo", endpoint)),
+            jwks: Some(format!("{}/.well-known/jwks", endpoint)),
+        }
+    }
+
+    fn identity(&self, claims: Value) -> Result<CasdoorUser, String> {
+        serde_json::from_value::<CasdoorUser>(claims)
+            .map_err(|err| format!("Token claims are not a casdoor user: {}", err))
+    }
+
+    /// Casdoor issues client credentials tokens for a pseudo user of type "application".
+    fn client(&self, claims: &Value) -> Option<String> {
+        match claims.get("type").and_then(Value::as_str) {
+            Some("application") => client_id(claims),
+            _ => None,
+        }
+    }
+}
+
+/// A standard OpenID Connect provider.
+/// Users are named by `username_claim` and belong to the configured organization.
+pub struct Oidc {
+    pub org_name: String,
+    pub username_claim: String,
+}
+
+impl Provider for Oidc {
+    fn default_endpoints(&self, _endpoint: &str) -> Endpoints {
+        Endpoints::default()
+    }
+
+    fn identity(&self, claims: Value) -> Result<CasdoorUser, String> {
+        let claim = |name: &str| {
+            claims
+                .get(name)
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .unwrap_or_default()
+        };
+        let name = match claim(&self.username_claim) {
+            name if name.is_empty() => claim("sub"),
+            name => name,
+        };
+        if name.is_empty() {
+            return Err(format!(
+                "Token has neither \"{}\" nor \"sub\" claim",
+                self.username_claim
+            ));
+        }
+
+        Ok(CasdoorUser {
+            owner: self.org_name.clone(),
+            name,
+            id: claim("sub"),
+            display_name: claim("name"),
+            email: claim("email"),
+            phone: claim("phone_number"),
+            avatar: claim("picture"),
+            homepage: claim("website"),
+            language: claim("locale"),
+            ..Default::default()
+        })
+    }
+}
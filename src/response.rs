@@ -4,24 +4,40 @@ use serde_derive::{Deserialize, Serialize};
 pub struct TokenResponse {
     pub access_token: String,
     pub expires_in: u32,
+    // Not always returned when refreshing
+    #[serde(default)]
     pub id_token: String,
+    #[serde(default)]
     pub refresh_token: String,
+    #[serde(default)]
     pub scope: String,
     pub token_type: String,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ActiveResponse {
     pub active: bool,
-    pub aud: Option<Vec<String>>,
+    // A string or an array of strings
+    pub aud: Option<serde_json::Value>,
     pub client_id: Option<String>,
-    pub exp: Option<i32>,
-    pub iat: Option<i32>,
+    pub exp: Option<i64>,
+    pub iat: Option<i64>,
     pub iss: Option<String>,
     pub jti: Option<String>,
-    pub nbf: Option<i32>,
+    pub nbf: Option<i64>,
     pub scope: Option<String>,
     pub sub: Option<String>,
     pub token_type: Option<String>,
     pub username: Option<String>,
 }
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DiscoveryResponse {
+    pub issuer: Option<String>,
+    pub authorization_endpoint: Option<String>,
+    pub token_endpoint: Option<String>,
+    pub introspection_endpoint: Option<String>,
+    pub revocation_endpoint: Option<String>,
+    pub userinfo_endpoint: Option<String>,
+    pub jwks_uri: Option<String>,
+}
@@ -0,0 +1,136 @@
+use crate::entity::{RouteClass, RouteConfig};
+use crate::CONFIG;
+
+/// Class of the request given by the first matching route in `routes`.
+/// Requests matching no route are authorized.
+pub fn classify(host: Option<&str>, path: &str, method: &str) -> RouteClass {
+    classify_by(CONFIG.routes.as_deref().unwrap_or_default(), host, path, method)
+}
+
+fn classify_by(routes: &[RouteConfig], host: Option<&str>, path: &str, method: &str) -> RouteClass {
+    routes
+        .iter()
+        .find(|route| {
+            let host_matches = match (&route.host, host) {
+                (None, _) => true,
+                (Some(pattern), Some(host)) => host_matches(pattern, host),
+                (Some(_), None) => false,
+            };
+            let method_matches = route
+                .methods
+                .as_ref()
+                .is_none_or(|methods| methods.iter().any(|allowed| allowed.eq_ignore_ascii_case(method)));
+            host_matches && method_matches && wildcard_match(&route.path, path)
+        })
+        .map(|route| route.class)
+        .unwrap_or_default()
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    // The port is only compared if the pattern names one
+    let host = match pattern.contains(':') {
+        true => host,
+        false => match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|c| c.is_ascii_digit()) => name,
+            _ => host,
+        },
+    };
+    // Host names are case insensitive
+    wildcard_match(&pattern.to_ascii_lowercase(), &host.to_ascii_lowercase())
+}
+
+/// Whether the text matches the pattern, where `*` matches any characters including `/`.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text it has taken up to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the last `*` take one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(class: RouteClass, host: Option<&str>, path: &str, methods: Option<&[&str]>) -> RouteConfig {
+        RouteConfig {
+            class,
+            host: host.map(str::to_string),
+            path: path.to_string(),
+            methods: methods.map(|methods| methods.iter().map(|method| method.to_string()).collect()),
+        }
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("/static/*", "/static/app.js"));
+        // `*` crosses segments
+        assert!(wildcard_match("/static/*", "/static/js/vendor/app.js"));
+        assert!(wildcard_match("/api/*/health", "/api/v1/orders/health"));
+        assert!(wildcard_match("*.js", "/a/b.js"));
+        assert!(wildcard_match("/static/*", "/static/"));
+        assert!(wildcard_match("/a**b", "/ab"));
+        assert!(wildcard_match("/exact", "/exact"));
+
+        assert!(!wildcard_match("/static/*", "/static"));
+        assert!(!wildcard_match("/static/*", "/api/static/app.js"));
+        assert!(!wildcard_match("/api/*/health", "/api/v1/healthz"));
+        assert!(!wildcard_match("/exact", "/exact/"));
+        assert!(!wildcard_match("", "/"));
+    }
+
+    #[test]
+    fn hosts_and_ports() {
+        assert!(host_matches("api.example.com", "api.example.com"));
+        assert!(host_matches("api.example.com", "API.Example.com"));
+        assert!(host_matches("*.example.com", "cdn.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+
+        // The port is ignored unless the pattern names one
+        assert!(host_matches("api.example.com", "api.example.com:8443"));
+        assert!(host_matches("api.example.com:8443", "api.example.com:8443"));
+        assert!(!host_matches("api.example.com:8443", "api.example.com"));
+        assert!(!host_matches("api.example.com:8443", "api.example.com:443"));
+        assert!(host_matches("api.example.com:*", "api.example.com:443"));
+        // Only a numeric port is stripped
+        assert!(!host_matches("api.example.com", "api.example.com:evil"));
+        assert!(!host_matches("api.example.com", "api.example.com.evil.com:443"));
+    }
+
+    #[test]
+    fn first_matching_route_wins() {
+        let routes = [
+            route(RouteClass::Authorized, None, "/static/admin/*", None),
+            route(RouteClass::Public, None, "/static/*", None),
+            route(RouteClass::Public, Some("docs.example.com"), "/*", Some(&["GET", "head"])),
+            route(RouteClass::Authenticated, None, "/api/me", None),
+        ];
+        let classify = |host, path, method| classify_by(&routes, host, path, method);
+
+        assert_eq!(classify(None, "/static/app.js", "POST"), RouteClass::Public);
+        assert_eq!(classify(None, "/static/admin/app.js", "GET"), RouteClass::Authorized);
+        assert_eq!(classify(Some("docs.example.com:8080"), "/guide", "HEAD"), RouteClass::Public);
+        assert_eq!(classify(Some("docs.example.com"), "/guide", "POST"), RouteClass::Authorized);
+        // Host routes never match requests without a forwarded host
+        assert_eq!(classify(None, "/guide", "GET"), RouteClass::Authorized);
+        assert_eq!(classify(Some("docs.example.com"), "/api/me", "GET"), RouteClass::Public);
+        assert_eq!(classify(Some("app.example.com"), "/api/me", "GET"), RouteClass::Authenticated);
+        assert_eq!(classify(None, "/api/users", "GET"), RouteClass::Authorized);
+    }
+}
@@ -0,0 +1,81 @@
+use std::error::Error;
+
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use ring::signature::{EcdsaKeyPair, KeyPair, RsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::dpop::thumbprint;
+
+/// Key of the gateway signing the internal tokens forwarded to upstreams.
+pub struct Signer {
+    key: EncodingKey,
+    header: Header,
+    jwk: Value,
+}
+
+fn b64(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+impl Signer {
+    /// Load a PEM encoded PKCS#8 private key of RSA or EC P-256,
+    /// a PKCS#1 RSA private key is accepted as well.
+    /// The key id is the JWK thumbprint of its public key.
+    pub fn load(text: &str) -> Result<Self, Box<dyn Error>> {
+        let pem = pem::parse(text.trim())?;
+        let rsa = match pem.tag.as_str() {
+            "PRIVATE KEY" => RsaKeyPair::from_pkcs8(&pem.contents).ok(),
+            "RSA PRIVATE KEY" => Some(RsaKeyPair::from_der(&pem.contents).map_err(|err| err.to_string())?),
+            label => return Err(format!("Unsupported PEM label \"{}\"", label).into()),
+        };
+
+        let (alg, key, jwk) = match rsa {
+            Some(pair) => {
+                let public = pair.public_key();
+                let jwk = json!({
+                    "kty": "RSA",
+                    "n": b64(public.modulus().big_endian_without_leading_zero()),
+                    "e": b64(public.exponent().big_endian_without_leading_zero()),
+                });
+                // Takes both PKCS#1 and PKCS#8
+                (Algorithm::RS256, EncodingKey::from_rsa_pem(text.trim().as_bytes())?, jwk)
+            }
+            None => {
+                let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pem.contents)
+                    .map_err(|_| "Private key is neither RSA nor EC P-256")?;
+                // Uncompressed point
+                let point = pair.public_key().as_ref();
+                let jwk = json!({
+                    "kty": "EC",
+                    "crv": "P-256",
+                    "x": b64(&point[1..33]),
+                    "y": b64(&point[33..65]),
+                });
+                (Algorithm::ES256, EncodingKey::from_ec_der(&pem.contents), jwk)
+            }
+        };
+
+        let kid = thumbprint(&jwk)?;
+        let mut jwk = jwk;
+        jwk["kid"] = json!(kid);
+        jwk["alg"] = json!(alg);
+        jwk["use"] = json!("sig");
+        let mut header = Header::new(alg);
+        header.kid = Some(kid);
+        Ok(Self { key, header, jwk })
+    }
+
+    pub fn kid(&self) -> &str {
+        self.header.kid.as_deref().unwrap_or_default()
+    }
+
+    /// The public key as a JWK Set for upstreams to verify the tokens.
+    pub fn jwks(&self) -> Value {
+        json!({ "keys": [self.jwk] })
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, jsonwebtoken::errors::Error> {
+        jsonwebtoken::encode(&self.header, claims, &self.key)
+    }
+}